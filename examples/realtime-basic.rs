@@ -1,3 +1,4 @@
+use voice_recognition::audio;
 use voice_recognition::realtime::*;
 use std::{
     path::PathBuf,
@@ -37,10 +38,10 @@ async fn main() {
         .join("example.wav");
 
     let file = File::open(test_file_path).await.unwrap();
+    let (wav_info, wav_data) = audio::probe_async(file).await.unwrap();
 
     let mut config: SessionConfig = Default::default();
-    let audio_config = models::AudioFormat::new(models::audio_format::Type::File);
-    config.audio_format = Some(audio_config);
+    config.audio_format = Some(wav_info.format.to_audio_format().unwrap());
 
     let mock_store = Arc::new(Mutex::new(MockStore::new()));
     let mock_store_clone = mock_store.clone();
@@ -60,11 +61,11 @@ async fn main() {
         }
     });
 
-    let run_task = { rt_session.run(config, file) };
+    let run_task = { rt_session.run(config, wav_data) };
 
     try_join!(
         async move { message_task.await.map_err(anyhow::Error::from) },
-        run_task
+        async move { run_task.await.map_err(anyhow::Error::from) }
     )
     .unwrap();
 
@@ -0,0 +1,168 @@
+//! Conversion of samples between the encodings in SampleFormat, including G.711 mu-law and A-law.
+//!
+//! Samples are converted through f32 in the range -1.0 to 1.0. The functions here are stateless and take whole samples,
+//! so for a stream split at arbitrary points, use an AudioPipeline, which keeps partial samples between chunks.
+use super::format::SampleFormat;
+
+/// The bias added to the magnitude of a sample before it is mu-law encoded
+const MULAW_BIAS: i32 = 0x84;
+/// The largest magnitude which can be mu-law encoded once the bias has been added
+const MULAW_CLIP: i32 = 32635;
+/// The largest 13-bit magnitude in each A-law segment
+const ALAW_SEGMENT_ENDS: [i32; 8] = [0x1F, 0x3F, 0x7F, 0xFF, 0x1FF, 0x3FF, 0x7FF, 0xFFF];
+
+/// Decodes a G.711 mu-law byte into a 16-bit linear sample
+pub fn mulaw_to_linear(byte: u8) -> i16 {
+    let byte = !byte;
+    let exponent = (byte >> 4) & 0x07;
+    let mantissa = (byte & 0x0F) as i32;
+    let magnitude = (((mantissa << 3) + MULAW_BIAS) << exponent) - MULAW_BIAS;
+    if byte & 0x80 != 0 {
+        -magnitude as i16
+    } else {
+        magnitude as i16
+    }
+}
+
+/// Encodes a 16-bit linear sample as a G.711 mu-law byte
+pub fn linear_to_mulaw(sample: i16) -> u8 {
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample as i32).abs().min(MULAW_CLIP) + MULAW_BIAS;
+    // the exponent is the position of the highest bit set above bit 7
+    let exponent = (31 - (magnitude as u32).leading_zeros()) as i32 - 7;
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+/// Decodes a G.711 A-law byte into a 16-bit linear sample
+pub fn alaw_to_linear(byte: u8) -> i16 {
+    let byte = byte ^ 0x55;
+    let segment = (byte >> 4) & 0x07;
+    let mantissa = ((byte & 0x0F) as i32) << 4;
+    let magnitude = match segment {
+        0 => mantissa + 8,
+        segment => (mantissa + 0x108) << (segment - 1),
+    };
+    if byte & 0x80 != 0 {
+        magnitude as i16
+    } else {
+        -magnitude as i16
+    }
+}
+
+/// Encodes a 16-bit linear sample as a G.711 A-law byte
+pub fn linear_to_alaw(sample: i16) -> u8 {
+    let sample = (sample as i32) >> 3;
+    let (magnitude, mask) = if sample >= 0 {
+        (sample, 0xD5)
+    } else {
+        (-sample - 1, 0x55)
+    };
+    let Some(segment) = ALAW_SEGMENT_ENDS.iter().position(|end| magnitude <= *end) else {
+        return 0x7F ^ mask;
+    };
+    let shift = if segment < 2 { 1 } else { segment };
+    let code = ((segment as i32) << 4) | ((magnitude >> shift) & 0x0F);
+    code as u8 ^ mask
+}
+
+/// Decodes little-endian samples into f32, ignoring any bytes left over after the last whole sample
+pub fn decode(data: &[u8], sample_format: SampleFormat) -> Vec<f32> {
+    let samples = data.chunks_exact(sample_format.bytes_per_sample());
+    match sample_format {
+        SampleFormat::U8 => samples
+            .map(|bytes| (bytes[0] as f32 - 128.0) / 128.0)
+            .collect(),
+        SampleFormat::S16 => samples
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+            .collect(),
+        SampleFormat::S24 => samples
+            .map(|bytes| {
+                // shift the sample into the top of an i32 to sign-extend it
+                let sample = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+                sample as f32 / 8388608.0
+            })
+            .collect(),
+        SampleFormat::S32 => samples
+            .map(|bytes| {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2147483648.0
+            })
+            .collect(),
+        SampleFormat::F32 => samples
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect(),
+        SampleFormat::F64 => samples
+            .map(|bytes| {
+                let mut sample = [0; 8];
+                sample.copy_from_slice(bytes);
+                f64::from_le_bytes(sample) as f32
+            })
+            .collect(),
+        SampleFormat::MuLaw => samples
+            .map(|bytes| mulaw_to_linear(bytes[0]) as f32 / 32768.0)
+            .collect(),
+        SampleFormat::ALaw => samples
+            .map(|bytes| alaw_to_linear(bytes[0]) as f32 / 32768.0)
+            .collect(),
+    }
+}
+
+/// Encodes f32 samples as little-endian samples, clipping anything outside -1.0 to 1.0 for the integer encodings
+pub fn encode(samples: &[f32], sample_format: SampleFormat) -> Vec<u8> {
+    let mut data = Vec::with_capacity(samples.len() * sample_format.bytes_per_sample());
+    for sample in samples {
+        match sample_format {
+            SampleFormat::U8 => data.push((scale(*sample, 128.0) + 128.0) as u8),
+            SampleFormat::S16 => data.extend((scale(*sample, 32768.0) as i16).to_le_bytes()),
+            SampleFormat::S24 => {
+                data.extend(&(scale(*sample, 8388608.0) as i32).to_le_bytes()[..3])
+            }
+            SampleFormat::S32 => data.extend((scale(*sample, 2147483648.0) as i32).to_le_bytes()),
+            SampleFormat::F32 => data.extend(sample.to_le_bytes()),
+            SampleFormat::F64 => data.extend((*sample as f64).to_le_bytes()),
+            SampleFormat::MuLaw => data.push(linear_to_mulaw(scale(*sample, 32768.0) as i16)),
+            SampleFormat::ALaw => data.push(linear_to_alaw(scale(*sample, 32768.0) as i16)),
+        }
+    }
+    data
+}
+
+/// Scales a sample to an integer range of -full_scale to full_scale - 1, rounding to the nearest step
+fn scale(sample: f32, full_scale: f64) -> f64 {
+    (sample as f64 * full_scale)
+        .round()
+        .clamp(-full_scale, full_scale - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_g711_round_trip() {
+        assert_eq!(mulaw_to_linear(0x00), -32124);
+        assert_eq!(mulaw_to_linear(0xFF), 0);
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0x2A), -32256);
+        for code in 0..=255u8 {
+            // mu-law has a negative zero, which encodes back as positive zero
+            if code != 0x7F {
+                assert_eq!(linear_to_mulaw(mulaw_to_linear(code)), code);
+            }
+            assert_eq!(linear_to_alaw(alaw_to_linear(code)), code);
+        }
+
+        let samples = [-1.0, -0.5, 0.0, 0.25, 0.999];
+        for sample_format in [SampleFormat::S16, SampleFormat::S24, SampleFormat::F32] {
+            let decoded = decode(&encode(&samples, sample_format), sample_format);
+            for (sample, decoded) in samples.iter().zip(decoded) {
+                assert!((sample - decoded).abs() < 1e-4);
+            }
+        }
+        let pcm = [0x00, 0x80, 0xFF, 0x7F];
+        assert_eq!(
+            encode(&decode(&pcm, SampleFormat::S16), SampleFormat::S16),
+            pcm
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! The error type returned by the audio helpers, so callers can tell unreadable input apart from audio the API cannot take.
+use std::fmt;
+use std::io;
+
+/// Shorthand for results whose error is an AudioError
+pub type Result<T, E = AudioError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong when inspecting or converting audio.
+#[derive(Debug)]
+pub enum AudioError {
+    /// The audio could not be read
+    Io(io::Error),
+    /// The audio is not a well-formed file of the expected kind, e.g. a WAV file without a fmt chunk
+    InvalidHeader(String),
+    /// The audio is well-formed, but in a format which is not supported, e.g. 12-bit PCM
+    Unsupported(String),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Io(err) => write!(f, "Failed to read audio: {}", err),
+            AudioError::InvalidHeader(message) => write!(f, "Invalid audio header: {}", message),
+            AudioError::Unsupported(message) => write!(f, "Unsupported audio: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for AudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AudioError {
+    fn from(err: io::Error) -> Self {
+        AudioError::Io(err)
+    }
+}
@@ -0,0 +1,141 @@
+//! Descriptions of uncompressed audio, independent of any one speech API.
+use std::time::Duration;
+
+#[cfg(feature = "realtime")]
+use super::error::{AudioError, Result};
+
+/// How each sample of uncompressed audio is encoded.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SampleFormat {
+    /// Unsigned 8-bit PCM
+    U8,
+    /// Signed 16-bit little-endian PCM
+    S16,
+    /// Signed 24-bit little-endian PCM, packed into 3 bytes
+    S24,
+    /// Signed 32-bit little-endian PCM
+    S32,
+    /// 32-bit little-endian IEEE float
+    F32,
+    /// 64-bit little-endian IEEE float
+    F64,
+    /// 8-bit G.711 mu-law
+    MuLaw,
+    /// 8-bit G.711 A-law
+    ALaw,
+}
+
+impl SampleFormat {
+    /// The size of a single sample in bytes
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::U8 | SampleFormat::MuLaw | SampleFormat::ALaw => 1,
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::S32 | SampleFormat::F32 => 4,
+            SampleFormat::F64 => 8,
+        }
+    }
+}
+
+/// The layout of a stream of uncompressed audio, with the samples of each channel interleaved.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct PcmFormat {
+    /// How each sample is encoded
+    pub sample_format: SampleFormat,
+    /// The number of frames per second
+    pub sample_rate: u32,
+    /// The number of channels
+    pub channels: u16,
+}
+
+impl PcmFormat {
+    /// Creates a format
+    pub fn new(sample_format: SampleFormat, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_format,
+            sample_rate,
+            channels,
+        }
+    }
+
+    /// The size of a frame, i.e. one sample for each channel, in bytes
+    pub fn bytes_per_frame(&self) -> usize {
+        self.sample_format.bytes_per_sample() * self.channels as usize
+    }
+
+    /// The number of bytes in a second of audio
+    pub fn bytes_per_second(&self) -> usize {
+        self.bytes_per_frame() * self.sample_rate as usize
+    }
+
+    /// The duration of the given number of bytes of audio, rounded down to whole frames
+    pub fn duration_of(&self, len: usize) -> Duration {
+        let frames = len / self.bytes_per_frame().max(1);
+        Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    /// The realtime AudioFormat for sending audio in this format as raw audio.
+    ///
+    /// # Errors
+    ///
+    /// The realtime API only takes mono audio, encoded as s16, f32 or mu-law, so anything else has to be converted first.
+    #[cfg(feature = "realtime")]
+    pub fn to_audio_format(&self) -> Result<crate::realtime::models::AudioFormat> {
+        use crate::realtime::models::audio_format::{Encoding, Type};
+        if self.channels != 1 {
+            return Err(AudioError::Unsupported(format!(
+                "the realtime API takes mono audio, not {} channels",
+                self.channels
+            )));
+        }
+        let encoding = match self.sample_format {
+            SampleFormat::S16 => Encoding::PcmS16le,
+            SampleFormat::F32 => Encoding::PcmF32le,
+            SampleFormat::MuLaw => Encoding::Mulaw,
+            sample_format => {
+                return Err(AudioError::Unsupported(format!(
+                    "the realtime API does not take {:?} samples",
+                    sample_format
+                )))
+            }
+        };
+        let sample_rate = i32::try_from(self.sample_rate)
+            .map_err(|_| AudioError::Unsupported("the sample rate is too high".to_owned()))?;
+        let mut audio_format = crate::realtime::models::AudioFormat::new(Type::Raw);
+        audio_format.encoding = Some(encoding);
+        audio_format.sample_rate = Some(sample_rate);
+        Ok(audio_format)
+    }
+}
+
+/// Reads the format of raw mono audio from a realtime AudioFormat. File audio has no fixed format, so it is rejected.
+#[cfg(feature = "realtime")]
+impl TryFrom<&crate::realtime::models::AudioFormat> for PcmFormat {
+    type Error = AudioError;
+
+    fn try_from(audio_format: &crate::realtime::models::AudioFormat) -> Result<Self> {
+        use crate::realtime::models::audio_format::{Encoding, Type};
+        if audio_format.type_value != Type::Raw {
+            return Err(AudioError::Unsupported(
+                "file audio has no fixed format".to_owned(),
+            ));
+        }
+        let sample_format = match audio_format.encoding {
+            Some(Encoding::PcmS16le) => SampleFormat::S16,
+            Some(Encoding::PcmF32le) => SampleFormat::F32,
+            Some(Encoding::Mulaw) => SampleFormat::MuLaw,
+            None => {
+                return Err(AudioError::Unsupported(
+                    "raw audio without an encoding".to_owned(),
+                ))
+            }
+        };
+        let sample_rate = audio_format
+            .sample_rate
+            .and_then(|sample_rate| u32::try_from(sample_rate).ok())
+            .filter(|sample_rate| *sample_rate > 0)
+            .ok_or_else(|| AudioError::Unsupported("raw audio without a sample rate".to_owned()))?;
+        Ok(Self::new(sample_format, sample_rate, 1))
+    }
+}
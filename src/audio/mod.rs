@@ -0,0 +1,25 @@
+//! Helpers for preparing audio before it is sent for transcription, shared by the realtime and batch clients.
+
+pub mod error;
+pub use error::AudioError;
+
+pub mod format;
+pub use format::{PcmFormat, SampleFormat};
+
+pub mod wav;
+#[cfg(feature = "realtime")]
+pub use wav::probe_async;
+pub use wav::{probe, WavInfo};
+
+pub mod convert;
+
+pub mod resample;
+pub use resample::Resampler;
+
+pub mod pipeline;
+#[cfg(feature = "realtime")]
+pub use pipeline::PipelineReader;
+pub use pipeline::{AudioPipeline, AudioStage};
+
+pub mod vad;
+pub use vad::{VadConfig, VadEvent, VadTimeline, VoiceActivityDetector};
@@ -0,0 +1,258 @@
+//! A conversion stage which transcodes, downmixes and resamples audio into the format a speech API expects, in place of an ffmpeg sidecar.
+#[cfg(feature = "realtime")]
+use std::io;
+#[cfg(feature = "realtime")]
+use std::pin::Pin;
+#[cfg(feature = "realtime")]
+use std::task::{ready, Context, Poll};
+#[cfg(feature = "realtime")]
+use tokio::io::{AsyncRead, ReadBuf};
+
+use super::convert::{decode, encode};
+use super::error::{AudioError, Result};
+use super::format::{PcmFormat, SampleFormat};
+use super::resample::Resampler;
+
+/// A stage which audio passes through on its way to a speech API, a chunk at a time.
+pub trait AudioStage {
+    /// Processes a chunk of audio, returning the audio to pass on
+    fn process(&mut self, data: &[u8]) -> Vec<u8>;
+    /// Returns anything held back at the end of the stream
+    fn flush(&mut self) -> Vec<u8>;
+}
+
+/// Converts a stream of audio from one PcmFormat to another, a chunk at a time.
+///
+/// Samples can be converted between any of the SampleFormats, multi-channel audio can be downmixed to mono by averaging
+/// the channels, and the sample rate can be changed with a high-quality resampler.
+/// Chunks do not need to hold whole frames, as any partial frame is kept for the next one.
+///
+/// # Example
+///
+/// ```
+/// let input = PcmFormat::new(SampleFormat::F32, 48000, 2);
+/// let mut pipeline = AudioPipeline::new(input, PcmFormat::new(SampleFormat::S16, 16000, 1)).unwrap();
+/// let converted = pipeline.process(&browser_audio);
+/// let tail = pipeline.flush();
+/// ```
+#[derive(Clone, Debug)]
+pub struct AudioPipeline {
+    input: PcmFormat,
+    output: PcmFormat,
+    /// The bytes of a partial input frame left over from the last chunk
+    pending: Vec<u8>,
+    resampler: Option<Resampler>,
+}
+
+impl AudioPipeline {
+    /// Creates a pipeline from the input format to the output format.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if the output has a different number of channels to the input and is not mono,
+    /// or if either format has no channels or a sample rate of 0.
+    pub fn new(input: PcmFormat, output: PcmFormat) -> Result<Self> {
+        for format in [&input, &output] {
+            if format.channels == 0 || format.sample_rate == 0 {
+                return Err(AudioError::Unsupported(
+                    "a format without channels or a sample rate".to_owned(),
+                ));
+            }
+        }
+        if output.channels != 1 && output.channels != input.channels {
+            return Err(AudioError::Unsupported(format!(
+                "mixing {} channels into {}",
+                input.channels, output.channels
+            )));
+        }
+        let resampler = (input.sample_rate != output.sample_rate).then(|| {
+            Resampler::new(
+                input.sample_rate,
+                output.sample_rate,
+                output.channels as usize,
+            )
+        });
+        Ok(Self {
+            input,
+            output,
+            pending: vec![],
+            resampler,
+        })
+    }
+
+    /// Creates a pipeline which only changes the encoding of the samples, e.g. from A-law to s16 for a push stream which takes PCM
+    pub fn transcoder(input: PcmFormat, sample_format: SampleFormat) -> Result<Self> {
+        let output = PcmFormat {
+            sample_format,
+            ..input
+        };
+        Self::new(input, output)
+    }
+
+    /// The format of the audio passed to process
+    pub fn input_format(&self) -> PcmFormat {
+        self.input
+    }
+
+    /// The format of the audio returned by process
+    pub fn output_format(&self) -> PcmFormat {
+        self.output
+    }
+
+    /// Converts a chunk of audio. The resampler holds back a few milliseconds of output until the next chunk or flush
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.input == self.output {
+            return data.to_vec();
+        }
+        self.pending.extend_from_slice(data);
+        let frame_len = self.input.bytes_per_frame();
+        let whole = self.pending.len() / frame_len * frame_len;
+        let samples = decode(&self.pending[..whole], self.input.sample_format);
+        self.pending.drain(..whole);
+
+        let samples = if self.output.channels == self.input.channels {
+            samples
+        } else {
+            downmix(&samples, self.input.channels as usize)
+        };
+        let samples = match self.resampler.as_mut() {
+            Some(resampler) => resampler.process(&samples),
+            None => samples,
+        };
+        encode(&samples, self.output.sample_format)
+    }
+
+    /// Returns the audio held back at the end of the stream, dropping any partial frame, and resets the pipeline
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.pending.clear();
+        match self.resampler.as_mut() {
+            Some(resampler) => encode(&resampler.flush(), self.output.sample_format),
+            None => vec![],
+        }
+    }
+}
+
+impl AudioStage for AudioPipeline {
+    fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        AudioPipeline::process(self, data)
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        AudioPipeline::flush(self)
+    }
+}
+
+/// Averages the channels of each interleaved frame into a single sample
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// An AsyncRead which passes everything read from another reader through an AudioPipeline, or any other AudioStage.
+///
+/// It can wrap any audio source passed to RealtimeSession::run, including an AudioSinkReader or another PipelineReader.
+///
+/// # Example
+///
+/// ```
+/// let (audio_sink, audio_reader) = rt_session.audio_sink();
+/// let pipeline = AudioPipeline::new(browser_format, PcmFormat::new(SampleFormat::S16, 16000, 1)).unwrap();
+/// config.audio_format = Some(pipeline.output_format().to_audio_format().unwrap());
+/// rt_session.run(config, PipelineReader::new(audio_reader, pipeline)).await.unwrap();
+/// ```
+#[cfg(feature = "realtime")]
+#[derive(Debug)]
+pub struct PipelineReader<R, S = AudioPipeline> {
+    inner: R,
+    pipeline: S,
+    buffer: Vec<u8>,
+    output: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+#[cfg(feature = "realtime")]
+impl<R: AsyncRead + Unpin, S: AudioStage> PipelineReader<R, S> {
+    /// Wraps a reader of audio in the pipeline's input format
+    pub fn new(inner: R, pipeline: S) -> Self {
+        Self {
+            inner,
+            pipeline,
+            buffer: vec![0; 8192],
+            output: vec![],
+            position: 0,
+            finished: false,
+        }
+    }
+}
+
+#[cfg(feature = "realtime")]
+impl<R: AsyncRead + Unpin, S: AudioStage + Unpin> AsyncRead for PipelineReader<R, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.position < this.output.len() {
+                let len = (this.output.len() - this.position).min(buf.remaining());
+                buf.put_slice(&this.output[this.position..this.position + len]);
+                this.position += len;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+            let mut read_buf = ReadBuf::new(&mut this.buffer);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            this.output = if read_buf.filled().is_empty() {
+                this.finished = true;
+                this.pipeline.flush()
+            } else {
+                this.pipeline.process(read_buf.filled())
+            };
+            this.position = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downmix_and_resample() {
+        let input = PcmFormat::new(SampleFormat::F32, 48000, 2);
+        let output = PcmFormat::new(SampleFormat::S16, 16000, 1);
+        let mut pipeline = AudioPipeline::new(input, output).unwrap();
+
+        // a second of a 440Hz tone, in the left channel only, fed in uneven chunks which split frames
+        let tone: Vec<u8> = (0..48000)
+            .flat_map(|frame| {
+                let sample = (2.0 * std::f32::consts::PI * 440.0 * frame as f32 / 48000.0).sin();
+                [sample, 0.0]
+            })
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let mut converted = vec![];
+        for chunk in tone.chunks(1001) {
+            converted.extend(pipeline.process(chunk));
+        }
+        converted.extend(pipeline.flush());
+
+        assert_eq!(converted.len(), 16000 * 2);
+        let samples: Vec<f32> = converted
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+            .collect();
+        // away from the edges, the tone keeps its frequency and half its amplitude
+        for (frame, sample) in samples.iter().enumerate().skip(100).take(15800) {
+            let expected =
+                0.5 * (2.0 * std::f32::consts::PI * 440.0 * frame as f32 / 16000.0).sin();
+            assert!((sample - expected).abs() < 0.01, "{} at {}", sample, frame);
+        }
+    }
+}
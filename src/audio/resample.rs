@@ -0,0 +1,188 @@
+//! Sample-rate conversion with a windowed-sinc polyphase filter.
+//!
+//! Each output sample is interpolated from the input samples within ZERO_CROSSINGS of it, weighted by a
+//! Kaiser-windowed sinc. When downsampling the cutoff of the filter is lowered to the new Nyquist frequency,
+//! so content which cannot be represented at the lower rate is filtered out rather than aliased.
+
+/// The number of zero crossings of the sinc on each side of the filter, which sets the steepness of the cutoff
+const ZERO_CROSSINGS: f64 = 16.0;
+/// The cutoff of the filter as a fraction of the Nyquist frequency, leaving room for the transition band
+const ROLLOFF: f64 = 0.94;
+/// The Kaiser window beta, trading the width of the transition band against stopband attenuation of about 80dB
+const KAISER_BETA: f64 = 8.0;
+/// The most filter phases kept. Ratios which need more are rounded to the nearest phase
+const MAX_PHASES: u64 = 1024;
+
+/// Converts interleaved f32 audio from one sample rate to another, keeping the filter state between calls to process.
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    /// The output rate divided by the greatest common divisor of the rates
+    up: u64,
+    /// The input rate divided by the greatest common divisor of the rates
+    down: u64,
+    /// The number of input frames either side of an output frame which contribute to it
+    half: usize,
+    phases: u64,
+    /// The coefficients of each phase of the filter, 2 * half of them each
+    table: Vec<f32>,
+    channels: usize,
+    /// The input frames still needed, each channel in turn. The first frame has the index base
+    history: Vec<Vec<f32>>,
+    base: u64,
+    /// The number of input frames taken, not counting the silence the history starts with
+    taken: u64,
+    next_out: u64,
+}
+
+impl Resampler {
+    /// Creates a resampler for audio with the given number of channels. Both rates must be above 0
+    pub fn new(input_rate: u32, output_rate: u32, channels: usize) -> Self {
+        let divisor = gcd(input_rate as u64, output_rate as u64).max(1);
+        let up = output_rate as u64 / divisor;
+        let down = input_rate as u64 / divisor;
+        let cutoff = (up as f64 / down as f64).min(1.0) * ROLLOFF;
+        let half = (ZERO_CROSSINGS / cutoff).ceil() as usize;
+        let phases = up.min(MAX_PHASES);
+        let window_norm = bessel_i0(KAISER_BETA);
+
+        let mut table = Vec::with_capacity(phases as usize * 2 * half);
+        for phase in 0..phases {
+            let frac = phase as f64 / phases as f64;
+            let row: Vec<f64> = (0..2 * half)
+                .map(|tap| {
+                    // the distance from the output position to the input frame this tap applies to
+                    let distance = frac + half as f64 - 1.0 - tap as f64;
+                    let x = distance / half as f64;
+                    let window = if x.abs() >= 1.0 {
+                        0.0
+                    } else {
+                        bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / window_norm
+                    };
+                    cutoff * sinc(cutoff * distance) * window
+                })
+                .collect();
+            // normalise each phase to unity gain, so silence stays silent and DC is unchanged
+            let sum: f64 = row.iter().sum();
+            table.extend(row.iter().map(|coefficient| (coefficient / sum) as f32));
+        }
+
+        Self {
+            up,
+            down,
+            half,
+            phases,
+            table,
+            channels,
+            // the filter starts half a window into silence
+            history: vec![vec![0.0; half]; channels],
+            base: 0,
+            taken: 0,
+            next_out: 0,
+        }
+    }
+
+    /// Resamples interleaved frames, returning all the interleaved output frames which can be produced so far.
+    /// The last few milliseconds are held back until more input arrives or flush is called
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        self.push(input);
+        self.taken += (input.len() / self.channels) as u64;
+        let mut output = vec![];
+        self.drain(None, &mut output);
+        output
+    }
+
+    /// Returns the output frames held back, as if the input were followed by silence, and resets the resampler
+    pub fn flush(&mut self) -> Vec<f32> {
+        let silence = vec![0.0; 2 * self.half * self.channels];
+        self.push(&silence);
+        // only the output frames which fall within the input, and not the silence after it, are kept
+        let limit = (self.taken * self.up).div_ceil(self.down);
+        let mut output = vec![];
+        self.drain(Some(limit), &mut output);
+        self.history = vec![vec![0.0; self.half]; self.channels];
+        self.base = 0;
+        self.taken = 0;
+        self.next_out = 0;
+        output
+    }
+
+    fn push(&mut self, input: &[f32]) {
+        for frame in input.chunks_exact(self.channels) {
+            for (history, sample) in self.history.iter_mut().zip(frame) {
+                history.push(*sample);
+            }
+        }
+    }
+
+    fn drain(&mut self, limit: Option<u64>, output: &mut Vec<f32>) {
+        let available = self.base + self.history[0].len() as u64;
+        let taps = 2 * self.half;
+        loop {
+            if limit.is_some_and(|limit| self.next_out >= limit) {
+                break;
+            }
+            let position = self.next_out * self.down;
+            // the index of the input frame at or before the output position, allowing for the silence at the start
+            let index = position / self.up + self.half as u64;
+            let phase = ((position % self.up) * self.phases + self.up / 2) / self.up;
+            let (index, phase) = if phase == self.phases {
+                (index + 1, 0)
+            } else {
+                (index, phase)
+            };
+            if index + self.half as u64 >= available {
+                break;
+            }
+            let coefficients = &self.table[phase as usize * taps..(phase as usize + 1) * taps];
+            let first = (index + 1 - self.half as u64 - self.base) as usize;
+            for history in &self.history {
+                let sum: f32 = history[first..first + taps]
+                    .iter()
+                    .zip(coefficients)
+                    .map(|(sample, coefficient)| sample * coefficient)
+                    .sum();
+                output.push(sum);
+            }
+            self.next_out += 1;
+        }
+        // forget the input frames which no later output frame needs
+        let needed = (self.next_out * self.down) / self.up + 1;
+        let drop = needed.saturating_sub(self.base) as usize;
+        let drop = drop.min(self.history[0].len());
+        for history in &mut self.history {
+            history.drain(..drop);
+        }
+        self.base += drop as u64;
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// The zeroth order modified Bessel function of the first kind, for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..50 {
+        term *= (half_x / k as f64) * (half_x / k as f64);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
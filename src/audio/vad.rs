@@ -0,0 +1,357 @@
+//! Energy-based voice activity detection, which gates out long silences before audio is sent for transcription.
+//!
+//! Audio is split into short frames and each frame whose level is above a threshold counts as speech. After speech,
+//! audio keeps flowing for a hangover period so pauses between words are not cut, and a little audio before each onset
+//! is held back as pre-roll so the start of a word is not clipped. Everything else is dropped, so a long silence is
+//! compressed to at most the hangover plus the pre-roll, and a hangover and pre-roll of zero suppress it entirely.
+//!
+//! Dropping audio moves every later moment earlier in the stream the server sees. A VadTimeline records where audio
+//! was dropped, so the times in results can be mapped back onto the timeline of the original audio.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::convert::decode;
+use super::error::{AudioError, Result};
+use super::format::PcmFormat;
+use super::pipeline::AudioStage;
+
+/// Configures a VoiceActivityDetector.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VadConfig {
+    /// The level in dBFS above which a frame counts as speech. Defaults to -45.0.
+    pub threshold_db: f32,
+    /// The length of audio each decision is made on. Defaults to 20 milliseconds.
+    pub frame_duration: Duration,
+    /// How long audio keeps being sent after the level drops below the threshold. Defaults to 300 milliseconds.
+    pub hangover: Duration,
+    /// How much of the silence before an onset of speech is sent with it. Defaults to 200 milliseconds.
+    pub pre_roll: Duration,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -45.0,
+            frame_duration: Duration::from_millis(20),
+            hangover: Duration::from_millis(300),
+            pre_roll: Duration::from_millis(200),
+        }
+    }
+}
+
+/// A segment of the original audio, reported by a VadTimeline once it has ended. Times are in seconds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VadEvent {
+    /// Audio which was sent, from the first frame above the threshold to the end of the hangover
+    Speech {
+        /// When the segment started
+        start: f32,
+        /// When the segment ended
+        end: f32,
+    },
+    /// Audio between two segments of speech, of which only the pre-roll was sent
+    Silence {
+        /// When the segment started
+        start: f32,
+        /// When the segment ended
+        end: f32,
+    },
+}
+
+/// Gates the silence out of a stream of audio, a chunk at a time, leaving the format of the audio unchanged.
+///
+/// The gated audio can be passed to RealtimeSession::run with a PipelineReader, or written to the Microsoft push stream
+/// with PipelinePushStream::set_vad. The VadTimeline from timeline reports the segments found and maps times back.
+///
+/// # Example
+///
+/// ```
+/// let vad = VoiceActivityDetector::new(PcmFormat::new(SampleFormat::S16, 16000, 1), VadConfig::default()).unwrap();
+/// rt_session.set_vad_timeline(Some(vad.timeline()));
+/// rt_session.run(config, PipelineReader::new(audio_reader, vad)).await.unwrap();
+/// ```
+#[derive(Debug)]
+pub struct VoiceActivityDetector {
+    format: PcmFormat,
+    threshold: f32,
+    /// The length of a decision frame, in audio frames
+    frame_len: u64,
+    hangover_frames: u32,
+    pre_roll_frames: usize,
+    /// The bytes of a partial decision frame left over from the last chunk
+    pending: Vec<u8>,
+    /// The silent decision frames which may still be sent as pre-roll, along with their position in the original audio
+    pre_roll: VecDeque<(u64, Vec<u8>)>,
+    in_speech: bool,
+    hangover_left: u32,
+    /// Where the current segment started in the original audio, in audio frames
+    segment_start: u64,
+    /// The number of audio frames taken in and passed on
+    original: u64,
+    sent: u64,
+    timeline: VadTimeline,
+}
+
+impl VoiceActivityDetector {
+    /// Creates a detector for audio in the given format.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if the format has no channels or a sample rate of 0, or if the frame duration is
+    /// shorter than one audio frame.
+    pub fn new(format: PcmFormat, config: VadConfig) -> Result<Self> {
+        if format.channels == 0 || format.sample_rate == 0 {
+            return Err(AudioError::Unsupported(
+                "a format without channels or a sample rate".to_owned(),
+            ));
+        }
+        let to_frames =
+            |duration: Duration| (duration.as_secs_f64() * format.sample_rate as f64) as u64;
+        let frame_len = to_frames(config.frame_duration);
+        if frame_len == 0 {
+            return Err(AudioError::Unsupported(format!(
+                "a VAD frame of {:?}",
+                config.frame_duration
+            )));
+        }
+        Ok(Self {
+            format,
+            // compared against the mean square of the samples, to save taking the log of every frame
+            threshold: 10f32.powf(config.threshold_db / 10.0),
+            frame_len,
+            hangover_frames: to_frames(config.hangover).div_ceil(frame_len) as u32,
+            pre_roll_frames: to_frames(config.pre_roll).div_ceil(frame_len) as usize,
+            pending: vec![],
+            pre_roll: VecDeque::new(),
+            in_speech: false,
+            hangover_left: 0,
+            segment_start: 0,
+            original: 0,
+            sent: 0,
+            timeline: VadTimeline::new(format.sample_rate),
+        })
+    }
+
+    /// Returns a handle to the events and time mapping of the detector, which can be kept once the detector is moved
+    pub fn timeline(&self) -> VadTimeline {
+        self.timeline.clone()
+    }
+
+    /// The format of the audio passed in and returned
+    pub fn format(&self) -> PcmFormat {
+        self.format
+    }
+
+    /// Gates a chunk of audio, returning the audio to send. A decision frame is held back until it is complete
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        let frame_bytes = self.frame_len as usize * self.format.bytes_per_frame();
+        let mut output = vec![];
+        while self.pending.len() >= frame_bytes {
+            let frame: Vec<u8> = self.pending.drain(..frame_bytes).collect();
+            self.decide(frame, &mut output);
+        }
+        output
+    }
+
+    /// Ends the stream, returning the rest of the audio if it is speech and reporting the last segment
+    pub fn flush(&mut self) -> Vec<u8> {
+        let whole =
+            self.pending.len() / self.format.bytes_per_frame() * self.format.bytes_per_frame();
+        let frame: Vec<u8> = self.pending.drain(..).take(whole).collect();
+        let mut output = vec![];
+        let start = self.original;
+        self.original += (frame.len() / self.format.bytes_per_frame()) as u64;
+        if self.in_speech {
+            self.emit(start, &frame, &mut output);
+        }
+        self.pre_roll.clear();
+        self.end_segment_at(self.original);
+        output
+    }
+
+    fn decide(&mut self, frame: Vec<u8>, output: &mut Vec<u8>) {
+        let samples = decode(&frame, self.format.sample_format);
+        let power =
+            samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+        let start = self.original;
+        self.original += self.frame_len;
+
+        if power >= self.threshold {
+            self.hangover_left = self.hangover_frames;
+            if !self.in_speech {
+                self.end_segment_at(start);
+                self.in_speech = true;
+                for (position, frame) in std::mem::take(&mut self.pre_roll) {
+                    self.emit(position, &frame, output);
+                }
+            }
+            self.emit(start, &frame, output);
+        } else if self.in_speech && self.hangover_left > 0 {
+            self.hangover_left -= 1;
+            self.emit(start, &frame, output);
+        } else {
+            if self.in_speech {
+                self.end_segment_at(start);
+                self.in_speech = false;
+            }
+            self.pre_roll.push_back((start, frame));
+            if self.pre_roll.len() > self.pre_roll_frames {
+                self.pre_roll.pop_front();
+            }
+        }
+    }
+
+    /// Passes on audio which started at the given position in the original audio, noting any gap before it
+    fn emit(&mut self, position: u64, frame: &[u8], output: &mut Vec<u8>) {
+        self.timeline.anchor(self.sent, position);
+        self.sent += (frame.len() / self.format.bytes_per_frame()) as u64;
+        output.extend_from_slice(frame);
+    }
+
+    /// Reports the current segment as ending at the given position and starts the next one there
+    fn end_segment_at(&mut self, position: u64) {
+        if position > self.segment_start {
+            self.timeline
+                .event(self.in_speech, self.segment_start, position);
+        }
+        self.segment_start = position;
+    }
+}
+
+impl AudioStage for VoiceActivityDetector {
+    fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        VoiceActivityDetector::process(self, data)
+    }
+
+    fn flush(&mut self) -> Vec<u8> {
+        VoiceActivityDetector::flush(self)
+    }
+}
+
+/// The segments found by a VoiceActivityDetector and where it dropped audio, shared with the detector.
+///
+/// Times in results from the server are relative to the audio sent, which to_original turns into times
+/// in the original audio. A RealtimeSession given the timeline with set_vad_timeline does this for every result.
+#[derive(Clone, Debug)]
+pub struct VadTimeline {
+    sample_rate: u32,
+    inner: Arc<Mutex<TimelineInner>>,
+}
+
+#[derive(Debug, Default)]
+struct TimelineInner {
+    /// Pairs of positions in the sent audio and the original audio, in audio frames, from which the two run in step
+    anchors: Vec<(u64, u64)>,
+    events: Vec<VadEvent>,
+}
+
+impl VadTimeline {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            inner: Arc::default(),
+        }
+    }
+
+    /// Maps a time in seconds from the start of the audio sent to the same moment in the original audio
+    pub fn to_original(&self, time: f32) -> f32 {
+        let inner = self.inner.lock().unwrap();
+        let sent = (time as f64 * self.sample_rate as f64) as u64;
+        let index = inner.anchors.partition_point(|(anchor, _)| *anchor <= sent);
+        match index.checked_sub(1).map(|index| inner.anchors[index]) {
+            Some((anchor, original)) => time + (original - anchor) as f32 / self.sample_rate as f32,
+            None => time,
+        }
+    }
+
+    /// Returns the segments which have ended since the last call
+    pub fn take_events(&self) -> Vec<VadEvent> {
+        std::mem::take(&mut self.inner.lock().unwrap().events)
+    }
+
+    fn anchor(&self, sent: u64, original: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let offset = inner
+            .anchors
+            .last()
+            .map_or(0, |(anchor, anchor_original)| anchor_original - anchor);
+        if original - sent != offset {
+            inner.anchors.push((sent, original));
+        }
+    }
+
+    fn event(&self, speech: bool, start: u64, end: u64) {
+        let start = start as f32 / self.sample_rate as f32;
+        let end = end as f32 / self.sample_rate as f32;
+        let event = if speech {
+            VadEvent::Speech { start, end }
+        } else {
+            VadEvent::Silence { start, end }
+        };
+        self.inner.lock().unwrap().events.push(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::convert::encode;
+    use crate::audio::format::SampleFormat;
+
+    #[test]
+    fn test_gate_silence() {
+        let format = PcmFormat::new(SampleFormat::S16, 1000, 1);
+        let config = VadConfig {
+            threshold_db: -30.0,
+            frame_duration: Duration::from_millis(10),
+            hangover: Duration::from_millis(20),
+            pre_roll: Duration::from_millis(10),
+        };
+        let mut vad = VoiceActivityDetector::new(format, config).unwrap();
+        let timeline = vad.timeline();
+
+        // a second of silence, half a second of tone, then another second of silence and a tenth of a second of tone
+        let tone = |len: usize| (0..len).map(|i| if i % 2 == 0 { 0.5 } else { -0.5 });
+        let samples: Vec<f32> = std::iter::repeat_n(0.0, 1000)
+            .chain(tone(500))
+            .chain(std::iter::repeat_n(0.0, 1000))
+            .chain(tone(100))
+            .collect();
+        let mut sent = vec![];
+        for chunk in encode(&samples, SampleFormat::S16).chunks(333) {
+            sent.extend(vad.process(chunk));
+        }
+        sent.extend(vad.flush());
+
+        // each burst is sent with 10ms of pre-roll, and the first with 20ms of hangover
+        assert_eq!(sent.len(), (10 + 500 + 20 + 10 + 100) * 2);
+        assert_eq!(
+            timeline.take_events(),
+            vec![
+                VadEvent::Silence {
+                    start: 0.0,
+                    end: 1.0
+                },
+                VadEvent::Speech {
+                    start: 1.0,
+                    end: 1.52
+                },
+                VadEvent::Silence {
+                    start: 1.52,
+                    end: 2.5
+                },
+                VadEvent::Speech {
+                    start: 2.5,
+                    end: 2.6
+                },
+            ]
+        );
+        // the first burst starts 10ms into what is sent, and the second 540ms in
+        assert!((timeline.to_original(0.0) - 0.99).abs() < 1e-4);
+        assert!((timeline.to_original(0.01) - 1.0).abs() < 1e-4);
+        assert!((timeline.to_original(0.54) - 2.5).abs() < 1e-4);
+        assert!((timeline.to_original(0.6) - 2.56).abs() < 1e-4);
+    }
+}
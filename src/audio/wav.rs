@@ -0,0 +1,296 @@
+//! Parsing of RIFF/WAVE headers, so WAV files can be sent as raw audio in a known format rather than as file audio.
+use std::io::{self, Read};
+use std::time::Duration;
+
+#[cfg(feature = "realtime")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use super::error::{AudioError, Result};
+use super::format::{PcmFormat, SampleFormat};
+
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+const WAVE_FORMAT_ALAW: u16 = 0x0006;
+const WAVE_FORMAT_MULAW: u16 = 0x0007;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The largest fmt chunk accepted. Real ones are 16, 18 or 40 bytes long
+const MAX_FMT_LEN: usize = 1024;
+/// How much of a chunk which is not needed is read at a time to skip over it
+const SKIP_LEN: usize = 8192;
+
+/// What the header of a WAV file says about the audio in it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct WavInfo {
+    /// The format of the samples in the data chunk
+    pub format: PcmFormat,
+    /// The length of the data chunk in bytes, or None if the header leaves it open, as WAV written on the fly often does
+    pub data_len: Option<u32>,
+}
+
+impl WavInfo {
+    /// The duration of the audio, if the length of the data chunk is known
+    pub fn duration(&self) -> Option<Duration> {
+        self.data_len
+            .map(|data_len| self.format.duration_of(data_len as usize))
+    }
+}
+
+/// Reads the header of a WAV file, returning its format along with a reader positioned at the start of the audio.
+///
+/// The reader stops at the end of the data chunk, so any chunks after it are not taken for audio.
+/// This works for any Read, e.g. a std::fs::File to check a file before submitting it as a batch job.
+///
+/// # Example
+///
+/// ```
+/// let (info, data) = probe(File::open("example.wav")?)?;
+/// println!("{:?} long, at {}Hz", info.duration(), info.format.sample_rate);
+/// ```
+///
+/// # Errors
+///
+/// This function errors if the file is not a WAV file, if it ends before the data chunk,
+/// or if its samples are in a format other than integer PCM, float, mu-law or A-law.
+pub fn probe<R: Read>(mut reader: R) -> Result<(WavInfo, io::Take<R>)> {
+    let mut parser = HeaderParser::default();
+    loop {
+        let mut buffer = vec![0; parser.wanted()];
+        reader.read_exact(&mut buffer).map_err(truncated)?;
+        if let Some(info) = parser.feed(&buffer)? {
+            let limit = info.data_len.map_or(u64::MAX, u64::from);
+            return Ok((info, reader.take(limit)));
+        }
+    }
+}
+
+/// Reads the header of a WAV file from an async reader, as probe does.
+///
+/// The reader it returns can be passed straight to RealtimeSession::run, with the format from WavInfo as raw audio.
+///
+/// # Example
+///
+/// ```
+/// let (info, data) = probe_async(File::open("example.wav").await?).await?;
+/// let mut config = SessionConfig::default();
+/// config.audio_format = Some(info.format.to_audio_format()?);
+/// rt_session.run(config, data).await?;
+/// ```
+#[cfg(feature = "realtime")]
+pub async fn probe_async<R: AsyncRead + Unpin>(
+    mut reader: R,
+) -> Result<(WavInfo, tokio::io::Take<R>)> {
+    let mut parser = HeaderParser::default();
+    loop {
+        let mut buffer = vec![0; parser.wanted()];
+        reader.read_exact(&mut buffer).await.map_err(truncated)?;
+        if let Some(info) = parser.feed(&buffer)? {
+            let limit = info.data_len.map_or(u64::MAX, u64::from);
+            return Ok((info, reader.take(limit)));
+        }
+    }
+}
+
+fn truncated(err: io::Error) -> AudioError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        AudioError::InvalidHeader("the file ended before the data chunk".to_owned())
+    } else {
+        AudioError::Io(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+enum State {
+    #[default]
+    Riff,
+    ChunkHeader,
+    Fmt(usize),
+    Skip(usize),
+}
+
+/// Parses a header a piece at a time, so the same parser serves sync and async readers
+#[derive(Debug, Default)]
+struct HeaderParser {
+    state: State,
+    format: Option<PcmFormat>,
+    /// The size in the RIFF header, which is left as 0 along with the data length when WAV is streamed
+    riff_len: u32,
+}
+
+impl HeaderParser {
+    /// The number of bytes to feed in next
+    fn wanted(&self) -> usize {
+        match self.state {
+            State::Riff => 12,
+            State::ChunkHeader => 8,
+            State::Fmt(len) => len,
+            State::Skip(len) => len.min(SKIP_LEN),
+        }
+    }
+
+    /// Takes exactly as many bytes as wanted returned, returning the header once the data chunk has been reached
+    fn feed(&mut self, bytes: &[u8]) -> Result<Option<WavInfo>> {
+        match self.state {
+            State::Riff => {
+                match &bytes[0..4] {
+                    b"RIFF" => {}
+                    b"RF64" | b"RIFX" => {
+                        return Err(AudioError::Unsupported(format!(
+                            "{} files",
+                            String::from_utf8_lossy(&bytes[0..4])
+                        )))
+                    }
+                    _ => return Err(AudioError::InvalidHeader("not a RIFF file".to_owned())),
+                }
+                if &bytes[8..12] != b"WAVE" {
+                    return Err(AudioError::InvalidHeader("not a WAVE file".to_owned()));
+                }
+                self.riff_len = u32_le(bytes, 4);
+                self.state = State::ChunkHeader;
+            }
+            State::ChunkHeader => {
+                let len = u32_le(bytes, 4);
+                // chunks are padded to an even length
+                let padded = len as usize + (len & 1) as usize;
+                match &bytes[0..4] {
+                    b"fmt " => {
+                        if !(16..=MAX_FMT_LEN).contains(&padded) {
+                            return Err(AudioError::InvalidHeader(format!(
+                                "a fmt chunk of {} bytes",
+                                len
+                            )));
+                        }
+                        self.state = State::Fmt(padded);
+                    }
+                    b"data" => {
+                        let format = self.format.ok_or_else(|| {
+                            AudioError::InvalidHeader(
+                                "the data chunk comes before the fmt chunk".to_owned(),
+                            )
+                        })?;
+                        let open_ended = len == u32::MAX || (len == 0 && self.riff_len == 0);
+                        let data_len = Some(len).filter(|_| !open_ended);
+                        return Ok(Some(WavInfo { format, data_len }));
+                    }
+                    _ if padded == 0 => {}
+                    _ => self.state = State::Skip(padded),
+                }
+            }
+            State::Fmt(_) => {
+                self.format = Some(parse_fmt(bytes)?);
+                self.state = State::ChunkHeader;
+            }
+            State::Skip(len) => {
+                self.state = match len - bytes.len() {
+                    0 => State::ChunkHeader,
+                    remaining => State::Skip(remaining),
+                };
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn parse_fmt(fmt: &[u8]) -> Result<PcmFormat> {
+    let mut tag = u16_le(fmt, 0);
+    let channels = u16_le(fmt, 2);
+    let sample_rate = u32_le(fmt, 4);
+    let bits_per_sample = u16_le(fmt, 14);
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        if fmt.len() < 40 {
+            return Err(AudioError::InvalidHeader(
+                "a WAVE_FORMAT_EXTENSIBLE fmt chunk without a sub-format".to_owned(),
+            ));
+        }
+        // the sub-format is a GUID whose first two bytes are the format tag
+        tag = u16_le(fmt, 24);
+    }
+    let sample_format = match (tag, bits_per_sample) {
+        (WAVE_FORMAT_PCM, 8) => SampleFormat::U8,
+        (WAVE_FORMAT_PCM, 16) => SampleFormat::S16,
+        (WAVE_FORMAT_PCM, 24) => SampleFormat::S24,
+        (WAVE_FORMAT_PCM, 32) => SampleFormat::S32,
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::F32,
+        (WAVE_FORMAT_IEEE_FLOAT, 64) => SampleFormat::F64,
+        (WAVE_FORMAT_MULAW, 8) => SampleFormat::MuLaw,
+        (WAVE_FORMAT_ALAW, 8) => SampleFormat::ALaw,
+        (tag, bits_per_sample) => {
+            return Err(AudioError::Unsupported(format!(
+                "format tag {:#06x} with {} bits per sample",
+                tag, bits_per_sample
+            )))
+        }
+    };
+    if channels == 0 || sample_rate == 0 {
+        return Err(AudioError::InvalidHeader(
+            "a fmt chunk without channels or a sample rate".to_owned(),
+        ));
+    }
+    Ok(PcmFormat::new(sample_format, sample_rate, channels))
+}
+
+fn u16_le(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_le(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        let file = std::fs::File::open("tests/data/example.wav").unwrap();
+        let (info, _) = probe(file).unwrap();
+        assert_eq!(info.format, PcmFormat::new(SampleFormat::S16, 48000, 1));
+        assert_eq!(info.data_len, Some(0x176780));
+
+        // WAVE_FORMAT_EXTENSIBLE float in stereo, with an odd-sized chunk before the data and another after it
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x28\0\0\0".to_vec();
+        wav.extend_from_slice(&[0xFE, 0xFF, 2, 0]);
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&128000u32.to_le_bytes());
+        wav.extend_from_slice(&[8, 0, 32, 0, 22, 0, 32, 0, 3, 0, 0, 0]);
+        wav.extend_from_slice(&[
+            3, 0, 0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xAA, 0, 0x38, 0x9B, 0x71,
+        ]);
+        wav.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        wav.extend_from_slice(b"data\x08\0\0\0");
+        wav.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        wav.extend_from_slice(b"LIST\x04\0\0\0abcd");
+
+        let (info, mut data) = probe(&wav[..]).unwrap();
+        assert_eq!(info.format, PcmFormat::new(SampleFormat::F32, 16000, 2));
+        let mut audio = vec![];
+        data.read_to_end(&mut audio).unwrap();
+        assert_eq!(audio, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // a data chunk which is really empty is not read past, unlike one whose length was left open
+        let mut empty = wav[..72].to_vec();
+        empty[4..8].copy_from_slice(&84u32.to_le_bytes());
+        empty.extend_from_slice(b"data\0\0\0\0");
+        empty.extend_from_slice(b"LIST\x04\0\0\0abcd");
+        let (info, mut data) = probe(&empty[..]).unwrap();
+        assert_eq!(info.data_len, Some(0));
+        audio.clear();
+        data.read_to_end(&mut audio).unwrap();
+        assert!(audio.is_empty());
+
+        empty[4..8].copy_from_slice(&0u32.to_le_bytes());
+        let (info, _) = probe(&empty[..]).unwrap();
+        assert_eq!(info.data_len, None);
+
+        assert!(matches!(
+            probe(&wav[..40]),
+            Err(AudioError::InvalidHeader(_))
+        ));
+    }
+}
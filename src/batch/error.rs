@@ -0,0 +1,179 @@
+//! The error type returned by the batch client, so callers can tell API errors, network failures and misconfiguration apart.
+use std::fmt;
+
+use super::models;
+
+/// Shorthand for results whose error is a BatchError
+pub type Result<T, E = BatchError> = std::result::Result<T, E>;
+
+/// An error response from the batch jobs API.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiError {
+    /// The HTTP status code
+    pub code: u16,
+    /// The type of the error, if the server sent one this version of the crate recognises
+    pub error_type: Option<models::error_response::Error>,
+    /// The details of the error, or the body of the response if it was not a standard error response
+    pub reason: String,
+}
+
+impl ApiError {
+    /// Builds an ApiError from the status and body of a failed request
+    pub(crate) fn from_response(code: u16, body: &[u8]) -> Self {
+        match serde_json::from_slice::<models::ErrorResponse>(body) {
+            Ok(response) => Self {
+                code,
+                error_type: Some(response.error),
+                reason: response.detail.unwrap_or_default(),
+            },
+            Err(_) => Self {
+                code,
+                error_type: None,
+                reason: String::from_utf8_lossy(body).into_owned(),
+            },
+        }
+    }
+
+    /// Returns true if the request might succeed if it is sent again, i.e. if the server was overloaded or rate limiting
+    pub fn is_retryable(&self) -> bool {
+        self.code == 429 || self.code >= 500
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "status {}", self.code)?;
+        if let Some(error_type) = self.error_type {
+            write!(f, " {:?}", error_type)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything that can go wrong when calling the batch jobs API.
+#[derive(Debug)]
+pub enum BatchError {
+    /// The API returned an error status
+    Api(ApiError),
+    /// The request could not be sent or the response could not be read, e.g. because of a network error
+    Transport(reqwest::Error),
+    /// The TLS backend of the client could not be set up
+    Tls(reqwest::Error),
+    /// The request did not complete in the time allowed
+    Timeout(reqwest::Error),
+    /// The server responded with something the client did not expect, e.g. a transcript which is not valid UTF-8
+    Protocol(String),
+    /// A request or response body could not be serialised or deserialised
+    Serialization(serde_json::Error),
+    /// A local file could not be read
+    Io(std::io::Error),
+    /// The client or the request was configured incorrectly, e.g. with an invalid URL or API key
+    Config(String),
+}
+
+impl BatchError {
+    /// Returns true if the request might succeed if it is sent again, e.g. after a network error or a 503 response
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BatchError::Api(err) => err.is_retryable(),
+            BatchError::Transport(_) | BatchError::Timeout(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::Api(err) => write!(f, "Request failed with {}", err),
+            BatchError::Transport(err) => write!(f, "Request failed: {}", err),
+            BatchError::Tls(err) => write!(f, "TLS error: {}", err),
+            BatchError::Timeout(err) => write!(f, "Timed out: {}", err),
+            BatchError::Protocol(message) => write!(f, "Protocol error: {}", message),
+            BatchError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            BatchError::Io(err) => write!(f, "IO error: {}", err),
+            BatchError::Config(message) => write!(f, "Invalid config: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BatchError::Transport(err) | BatchError::Tls(err) | BatchError::Timeout(err) => {
+                Some(err)
+            }
+            BatchError::Serialization(err) => Some(err),
+            BatchError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for BatchError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            BatchError::Timeout(err)
+        } else if err.is_builder() {
+            BatchError::Config(err.to_string())
+        } else if err.is_decode() {
+            BatchError::Protocol(err.to_string())
+        } else {
+            BatchError::Transport(err)
+        }
+    }
+}
+
+impl From<serde_json::Error> for BatchError {
+    fn from(err: serde_json::Error) -> Self {
+        BatchError::Serialization(err)
+    }
+}
+
+impl From<std::io::Error> for BatchError {
+    fn from(err: std::io::Error) -> Self {
+        BatchError::Io(err)
+    }
+}
+
+impl From<url::ParseError> for BatchError {
+    fn from(err: url::ParseError) -> Self {
+        BatchError::Config(format!("invalid url: {}", err))
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderValue> for BatchError {
+    fn from(err: reqwest::header::InvalidHeaderValue) -> Self {
+        BatchError::Config(format!("invalid API key: {}", err))
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BatchError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        BatchError::Protocol(format!("the response was not valid UTF-8: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_error_from_response() {
+        let body = br#"{"code": 404, "error": "Job not found", "detail": "No job with that id"}"#;
+        let err = ApiError::from_response(404, body);
+        assert_eq!(
+            err.error_type,
+            Some(models::error_response::Error::JobNotFound)
+        );
+        assert_eq!(err.reason, "No job with that id");
+        assert!(!BatchError::Api(err).is_retryable());
+
+        let err = ApiError::from_response(503, b"Service Unavailable");
+        assert_eq!(err.error_type, None);
+        assert!(BatchError::Api(err).is_retryable());
+    }
+}
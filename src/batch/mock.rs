@@ -0,0 +1,537 @@
+//! A local fake of the batch jobs API, following schemas/batch.yml, so BatchClient can be tested without an API key or a network.
+//!
+//! Jobs are held in memory. Each job runs for MockBatchConfig::job_duration and is then done, with a transcript of
+//! MockBatchConfig::transcript. Error responses can be injected with MockBatchServer::inject_failure.
+use axum::{
+    extract::{multipart::MultipartRejection, Multipart, Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{net::TcpListener, task::JoinHandle};
+use url::Url;
+
+use super::error::Result;
+use super::models::{self, error_response};
+
+/// Configures the behaviour of a MockBatchServer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockBatchConfig {
+    /// The API key requests must be authorised with. None accepts any key
+    pub api_key: Option<String>,
+    /// How long a job runs for before it is done. Defaults to zero, so jobs are done straight away
+    pub job_duration: Duration,
+    /// The transcript of every job, which is split into words half a second apart
+    pub transcript: String,
+    /// The duration of the audio of every job in seconds
+    pub audio_duration: i32,
+}
+
+impl Default for MockBatchConfig {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            job_duration: Duration::ZERO,
+            transcript: "hello world".to_owned(),
+            audio_duration: 1,
+        }
+    }
+}
+
+/// A fake batch jobs API listening on a local port, which is shut down when dropped.
+///
+/// # Example
+///
+/// ```
+/// let server = MockBatchServer::start(MockBatchConfig::default()).await.unwrap();
+/// let batch_client = BatchClient::new("ANY_KEY", Some(server.url())).unwrap();
+/// ```
+pub struct MockBatchServer {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    serve_task: JoinHandle<()>,
+}
+
+impl MockBatchServer {
+    /// Starts a server on a free port of localhost
+    pub async fn start(config: MockBatchConfig) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState {
+            config,
+            jobs: Mutex::new(vec![]),
+            failures: Mutex::new(VecDeque::new()),
+        });
+
+        let app = Router::new()
+            .route("/v2/jobs", get(get_jobs).post(submit_job))
+            .route("/v2/jobs/{id}", get(get_job).delete(delete_job))
+            .route("/v2/jobs/{id}/transcript", get(get_transcript))
+            .route("/v2/jobs/{id}/alignment", get(get_alignment))
+            .route("/v2/usage", get(get_usage))
+            .layer(middleware::from_fn_with_state(state.clone(), authorise))
+            .with_state(state.clone());
+        let serve_task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            serve_task,
+        })
+    }
+
+    /// The URL to pass to BatchClient::new
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/v2/", self.addr)).expect("a socket address is a valid host")
+    }
+
+    /// Makes the next request fail with the given HTTP status and error. Failures are used up in the order they are injected
+    pub fn inject_failure(&self, status: u16, error: error_response::Error) {
+        let mut failure = models::ErrorResponse::new(status as i32, error);
+        failure.detail = Some("injected by the mock server".to_owned());
+        self.state.failures.lock().unwrap().push_back(failure);
+    }
+
+    /// Returns the jobs submitted so far, including deleted ones, newest first
+    pub fn jobs(&self) -> Vec<models::JobDetails> {
+        let jobs = self.state.jobs.lock().unwrap();
+        jobs.iter()
+            .rev()
+            .map(|job| job.details(&self.state.config))
+            .collect()
+    }
+}
+
+impl Drop for MockBatchServer {
+    fn drop(&mut self) {
+        self.serve_task.abort();
+    }
+}
+
+struct MockState {
+    config: MockBatchConfig,
+    jobs: Mutex<Vec<MockJob>>,
+    failures: Mutex<VecDeque<models::ErrorResponse>>,
+}
+
+impl MockState {
+    /// Responds with f applied to the job with the given id, or with a 404 if there is no such job
+    fn with_job(
+        &self,
+        id: &str,
+        f: impl FnOnce(&mut MockJob, &MockBatchConfig) -> Response,
+    ) -> Response {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) => f(job, &self.config),
+            None => error(
+                StatusCode::NOT_FOUND,
+                error_response::Error::JobNotFound,
+                &format!("No job with id {}", id),
+            ),
+        }
+    }
+}
+
+struct MockJob {
+    id: String,
+    created_at: String,
+    submitted: Instant,
+    data_name: String,
+    config: models::JobConfig,
+    deleted: bool,
+}
+
+impl MockJob {
+    fn status(&self, config: &MockBatchConfig) -> models::job_details::Status {
+        if self.deleted {
+            models::job_details::Status::Deleted
+        } else if self.submitted.elapsed() >= config.job_duration {
+            models::job_details::Status::Done
+        } else {
+            models::job_details::Status::Running
+        }
+    }
+
+    fn details(&self, config: &MockBatchConfig) -> models::JobDetails {
+        let mut details = models::JobDetails::new(
+            self.created_at.clone(),
+            self.data_name.clone(),
+            self.id.clone(),
+            self.status(config),
+        );
+        details.duration = Some(config.audio_duration);
+        details.config = Some(Box::new(self.config.clone()));
+        details
+    }
+
+    /// Returns an error response if the job has no result to fetch
+    fn unfinished(&self, config: &MockBatchConfig) -> Option<Response> {
+        match self.status(config) {
+            models::job_details::Status::Done => None,
+            models::job_details::Status::Running => Some(error(
+                StatusCode::NOT_FOUND,
+                error_response::Error::TranscriptionNotReady,
+                "The job is still running",
+            )),
+            _ => Some(error(
+                StatusCode::GONE,
+                error_response::Error::JobExpired,
+                "The job has been deleted",
+            )),
+        }
+    }
+}
+
+/// Builds an error response in the format of the batch API
+fn error(status: StatusCode, error: error_response::Error, detail: &str) -> Response {
+    let mut body = models::ErrorResponse::new(status.as_u16() as i32, error);
+    body.detail = Some(detail.to_owned());
+    (status, Json(body)).into_response()
+}
+
+/// Fails the request if a failure has been injected or it has the wrong API key
+async fn authorise(State(state): State<Arc<MockState>>, request: Request, next: Next) -> Response {
+    let failure = state.failures.lock().unwrap().pop_front();
+    if let Some(failure) = failure {
+        let status =
+            StatusCode::from_u16(failure.code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return (status, Json(failure)).into_response();
+    }
+    if let Some(api_key) = &state.config.api_key {
+        let expected = format!("Bearer {}", api_key);
+        let authorised = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .is_some_and(|value| value.as_bytes() == expected.as_bytes());
+        if !authorised {
+            return error(
+                StatusCode::UNAUTHORIZED,
+                error_response::Error::PermissionDenied,
+                "Invalid API key",
+            );
+        }
+    }
+    next.run(request).await
+}
+
+async fn submit_job(
+    State(state): State<Arc<MockState>>,
+    multipart: std::result::Result<Multipart, MultipartRejection>,
+) -> Response {
+    let malformed = |detail: &str| {
+        error(
+            StatusCode::BAD_REQUEST,
+            error_response::Error::MalformedRequest,
+            detail,
+        )
+    };
+    let mut multipart = match multipart {
+        Ok(multipart) => multipart,
+        Err(_) => return malformed("Expected a multipart/form-data body"),
+    };
+    let mut data_name = None;
+    let mut config = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return malformed("The multipart/form-data body is invalid"),
+        };
+        let name = field.name().unwrap_or_default().to_owned();
+        let file_name = field.file_name().unwrap_or_default().to_owned();
+        let data = match field.bytes().await {
+            Ok(data) => data,
+            Err(_) => return malformed("The multipart/form-data body is invalid"),
+        };
+        match name.as_str() {
+            "data_file" => data_name = Some(file_name),
+            "config" => config = serde_json::from_slice::<models::JobConfig>(&data).ok(),
+            _ => {}
+        }
+    }
+    let data_name = match data_name {
+        Some(data_name) => data_name,
+        None => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                error_response::Error::MissingDataFile,
+                "The data_file part is missing",
+            )
+        }
+    };
+    let config = match config {
+        Some(config) => config,
+        None => return malformed("The config part is missing or invalid"),
+    };
+
+    let id: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(10)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    state.jobs.lock().unwrap().push(MockJob {
+        id: id.clone(),
+        created_at: timestamp(SystemTime::now()),
+        submitted: Instant::now(),
+        data_name,
+        config,
+        deleted: false,
+    });
+    (
+        StatusCode::CREATED,
+        Json(models::CreateJobResponse::new(id)),
+    )
+        .into_response()
+}
+
+async fn get_jobs(
+    State(state): State<Arc<MockState>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let include_deleted = query
+        .get("include_deleted")
+        .is_some_and(|value| value == "true");
+    let limit = query
+        .get("limit")
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(usize::MAX);
+    let jobs = state.jobs.lock().unwrap();
+    let jobs = jobs
+        .iter()
+        .rev()
+        .filter(|job| include_deleted || !job.deleted)
+        .take(limit)
+        .map(|job| job.details(&state.config))
+        .collect();
+    Json(models::RetrieveJobsResponse::new(jobs)).into_response()
+}
+
+async fn get_job(State(state): State<Arc<MockState>>, Path(id): Path<String>) -> Response {
+    state.with_job(&id, |job, config| {
+        Json(models::RetrieveJobResponse::new(job.details(config))).into_response()
+    })
+}
+
+async fn delete_job(
+    State(state): State<Arc<MockState>>,
+    Path(id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let force = query.get("force").is_some_and(|value| value == "true");
+    state.with_job(&id, |job, config| {
+        if job.status(config) == models::job_details::Status::Running && !force {
+            return error(
+                StatusCode::LOCKED,
+                error_response::Error::ResourceLocked,
+                "The job is still running, set force to delete it",
+            );
+        }
+        job.deleted = true;
+        Json(models::DeleteJobResponse::new(job.details(config))).into_response()
+    })
+}
+
+async fn get_transcript(
+    State(state): State<Arc<MockState>>,
+    Path(id): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let format = query.get("format").map(String::as_str).unwrap_or("json-v2");
+    state.with_job(&id, |job, config| {
+        if let Some(response) = job.unfinished(config) {
+            return response;
+        }
+        let words = words(&config.transcript);
+        match format {
+            "txt" => config.transcript.clone().into_response(),
+            "srt" => srt(&words).into_response(),
+            _ => Json(transcript_response(job, config, &words)).into_response(),
+        }
+    })
+}
+
+async fn get_alignment(State(state): State<Arc<MockState>>, Path(id): Path<String>) -> Response {
+    state.with_job(&id, |job, config| {
+        if let Some(response) = job.unfinished(config) {
+            return response;
+        }
+        if job.config.type_value != models::JobType::Alignment {
+            return error(
+                StatusCode::NOT_FOUND,
+                error_response::Error::JobIsNotOfTypeAlignment,
+                "The job is a transcription job",
+            );
+        }
+        let alignment: String = words(&config.transcript)
+            .iter()
+            .map(|(word, start_time, _)| format!("<time={:.2}>{}", start_time, word))
+            .collect::<Vec<_>>()
+            .join(" ");
+        alignment.into_response()
+    })
+}
+
+async fn get_usage(State(state): State<Arc<MockState>>) -> Response {
+    let jobs = state.jobs.lock().unwrap();
+    let mut details: Vec<models::UsageDetails> = vec![];
+    for job in jobs.iter() {
+        let language = job
+            .config
+            .transcription_config
+            .as_ref()
+            .map(|config| config.language.clone());
+        let duration_hrs = state.config.audio_duration as f32 / 3600.0;
+        match details
+            .iter_mut()
+            .find(|usage| usage.type_value == job.config.type_value && usage.language == language)
+        {
+            Some(usage) => {
+                usage.count += 1;
+                usage.duration_hrs += duration_hrs;
+            }
+            None => {
+                let mut usage = models::UsageDetails::new(
+                    models::JobMode::Batch,
+                    job.config.type_value,
+                    1,
+                    duration_hrs,
+                );
+                usage.language = language;
+                details.push(usage);
+            }
+        }
+    }
+    let mut summary = models::UsageDetails::new(
+        models::JobMode::Batch,
+        models::JobType::Transcription,
+        0,
+        0.0,
+    );
+    for usage in details.iter() {
+        summary.count += usage.count;
+        summary.duration_hrs += usage.duration_hrs;
+    }
+    let since = jobs
+        .first()
+        .map(|job| job.created_at.clone())
+        .unwrap_or_else(|| timestamp(SystemTime::now()));
+    Json(models::UsageResponse::new(
+        since,
+        timestamp(SystemTime::now()),
+        vec![summary],
+        details,
+    ))
+    .into_response()
+}
+
+/// Splits the transcript into words with their start and end times
+fn words(transcript: &str) -> Vec<(&str, f32, f32)> {
+    transcript
+        .split_whitespace()
+        .enumerate()
+        .map(|(index, word)| (word, index as f32 * 0.5, index as f32 * 0.5 + 0.5))
+        .collect()
+}
+
+fn transcript_response(
+    job: &MockJob,
+    config: &MockBatchConfig,
+    words: &[(&str, f32, f32)],
+) -> models::RetrieveTranscriptResponse {
+    let language = job
+        .config
+        .transcription_config
+        .as_ref()
+        .map(|config| config.language.clone())
+        .unwrap_or_else(|| "en".to_owned());
+    let results = words
+        .iter()
+        .map(|(word, start_time, end_time)| {
+            let mut result = models::RecognitionResult::new(
+                *start_time,
+                *end_time,
+                models::recognition_result::Type::Word,
+            );
+            result.alternatives = Some(vec![models::RecognitionAlternative::new(
+                (*word).to_owned(),
+                1.0,
+                language.clone(),
+            )]);
+            result
+        })
+        .collect();
+    let mut metadata =
+        models::RecognitionMetadata::new(job.created_at.clone(), job.config.type_value);
+    metadata.transcription_config = job.config.transcription_config.clone();
+    models::RetrieveTranscriptResponse::new(
+        "2.9".to_owned(),
+        models::JobInfo::new(
+            job.created_at.clone(),
+            job.data_name.clone(),
+            config.audio_duration,
+            job.id.clone(),
+        ),
+        metadata,
+        results,
+    )
+}
+
+/// Formats the words as SubRip subtitles, one word per subtitle
+fn srt(words: &[(&str, f32, f32)]) -> String {
+    let srt_time = |seconds: f32| {
+        let millis = (seconds * 1000.0).round() as u64;
+        format!(
+            "{:02}:{:02}:{:02},{:03}",
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1000 % 60,
+            millis % 1000
+        )
+    };
+    words
+        .iter()
+        .enumerate()
+        .map(|(index, (word, start_time, end_time))| {
+            format!(
+                "{}\n{} --> {}\n{}\n",
+                index + 1,
+                srt_time(*start_time),
+                srt_time(*end_time),
+                word
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats a time as an RFC 3339 timestamp in UTC, as the API does
+fn timestamp(time: SystemTime) -> String {
+    OffsetDateTime::from(time)
+        .format(&Rfc3339)
+        .expect("a time after 1970 can be formatted as RFC 3339")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_294_400_250);
+        assert_eq!(timestamp(time), "2024-03-01T12:00:00.25Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+}
@@ -1,18 +1,26 @@
 //! The main entry point for the batch jobs API. Provides a struct which wraps a client and comes with associated API methods.
 
-use anyhow::Result;
 use models::*;
 use reqwest::{
     header::{HeaderMap, HeaderValue, AUTHORIZATION},
     multipart::{Form, Part},
-    Client,
+    Certificate, Client, ClientBuilder, Identity, Proxy,
 };
 use std::fs;
 use url::Url;
 
+use crate::connection::{ConnectionOptions, TlsBackend};
+
 #[allow(missing_docs)]
 pub mod models;
 
+pub mod error;
+use error::Result;
+pub use error::{ApiError, BatchError};
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
 /// The default URL for the batch runtime.
 ///
 /// This is the standard URL for self-service customers, and some enterprise customers.
@@ -33,12 +41,25 @@ pub struct BatchClient {
 impl BatchClient {
     /// Instantiates a new batch client. Parses the provided URL or use the defaults
     pub fn new(api_key: &str, batch_url: Option<url::Url>) -> Result<Self> {
+        Self::with_connection_options(api_key, batch_url, &ConnectionOptions::default())
+    }
+
+    /// Instantiates a new batch client which connects as set out in the ConnectionOptions,
+    /// e.g. through a proxy, with a private CA or with rustls.
+    pub fn with_connection_options(
+        api_key: &str,
+        batch_url: Option<url::Url>,
+        options: &ConnectionOptions,
+    ) -> Result<Self> {
         let mut headers = HeaderMap::new();
 
         let auth_header = format!("Bearer {}", api_key);
         headers.append(AUTHORIZATION, HeaderValue::from_str(&auth_header)?);
 
-        let client = Client::builder().default_headers(headers).build()?;
+        let client = client_builder(options)?
+            .default_headers(headers)
+            .build()
+            .map_err(BatchError::Tls)?;
         let mut set_url = Url::parse(DEFAULT_BATCH_URL)?;
         if let Some(batch_url_set) = batch_url {
             set_url = batch_url_set
@@ -112,7 +133,7 @@ impl BatchClient {
             .text("config", config_text);
 
         let res = self.client.post(url).multipart(form).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<CreateJobResponse>(&result)?;
         Ok(serde_res)
@@ -138,7 +159,7 @@ impl BatchClient {
         let url = self.batch_url.join("jobs/")?.join(job_id)?;
 
         let res = self.client.get(url).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<RetrieveJobResponse>(&result)?;
         Ok(serde_res)
@@ -187,7 +208,7 @@ impl BatchClient {
         }
 
         let res = self.client.get(url).query(&queries).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<RetrieveJobsResponse>(&result)?;
         Ok(serde_res)
@@ -228,7 +249,7 @@ impl BatchClient {
         queries.push(("format".to_owned(), "json-v2".to_owned()));
 
         let res = self.client.get(url).query(&queries).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<RetrieveTranscriptResponse>(&result)?;
         Ok(serde_res)
@@ -269,7 +290,7 @@ impl BatchClient {
         queries.push(("format".to_owned(), "txt".to_owned()));
 
         let res = self.client.get(url).query(&queries).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = String::from_utf8(result.to_vec())?;
         Ok(serde_res)
@@ -310,7 +331,7 @@ impl BatchClient {
         queries.push(("format".to_owned(), "srt".to_owned()));
 
         let res = self.client.get(url).query(&queries).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<String>(&result)?;
         Ok(serde_res)
@@ -349,18 +370,88 @@ impl BatchClient {
         }
 
         let res = self.client.delete(url).query(&queries).send().await;
-        let result = res?.error_for_status()?.bytes().await?;
+        let result = response_bytes(res?).await?;
 
         let serde_res = serde_json::from_slice::<DeleteJobResponse>(&result)?;
         Ok(serde_res)
     }
 }
 
+/// Reads the body of a response, turning error statuses into BatchError::Api
+async fn response_bytes(res: reqwest::Response) -> Result<bytes::Bytes> {
+    let status = res.status();
+    let body = res.bytes().await?;
+    if status.is_client_error() || status.is_server_error() {
+        return Err(BatchError::Api(ApiError::from_response(
+            status.as_u16(),
+            &body,
+        )));
+    }
+    Ok(body)
+}
+
+/// Applies the ConnectionOptions to the builder of the HTTP client
+fn client_builder(options: &ConnectionOptions) -> Result<ClientBuilder> {
+    let mut builder = Client::builder();
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+    for pem in &options.root_certificates {
+        let certificate = Certificate::from_pem(pem).map_err(BatchError::Tls)?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    match options.tls_backend {
+        TlsBackend::NativeTls => {
+            builder = builder.use_native_tls();
+            if let Some(identity) = &options.client_identity {
+                let identity =
+                    Identity::from_pkcs8_pem(&identity.certificate_chain, &identity.private_key)
+                        .map_err(BatchError::Tls)?;
+                builder = builder.identity(identity);
+            }
+            builder = builder.danger_accept_invalid_hostnames(options.insecure);
+        }
+        #[cfg(feature = "rustls-tls")]
+        TlsBackend::Rustls => {
+            builder = builder.use_rustls_tls();
+            if let Some(identity) = &options.client_identity {
+                // rustls takes the key and the certificate chain as a single PEM bundle
+                let mut pem = identity.private_key.clone();
+                pem.push(b'\n');
+                pem.extend_from_slice(&identity.certificate_chain);
+                builder = builder.identity(Identity::from_pem(&pem).map_err(BatchError::Tls)?);
+            }
+        }
+        #[cfg(not(feature = "rustls-tls"))]
+        TlsBackend::Rustls => {
+            return Err(BatchError::Config(
+                "the rustls TLS backend requires the rustls-tls feature".to_owned(),
+            ))
+        }
+    }
+    Ok(builder.danger_accept_invalid_certs(options.insecure))
+}
+
 #[cfg(test)]
 mod tests {
+    use super::mock::{MockBatchConfig, MockBatchServer};
     use super::*;
     use std::path::PathBuf;
 
+    const API_KEY: &str = "test-key";
+
+    async fn start_server(job_duration: std::time::Duration) -> (MockBatchServer, BatchClient) {
+        let server = MockBatchServer::start(MockBatchConfig {
+            api_key: Some(API_KEY.to_owned()),
+            job_duration,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let batch_client = BatchClient::new(API_KEY, Some(server.url())).unwrap();
+        (server, batch_client)
+    }
+
     async fn submit_job_util(batch_client: &BatchClient) -> Result<CreateJobResponse> {
         let test_file_path = PathBuf::new()
             .join(".")
@@ -376,23 +467,41 @@ mod tests {
         batch_client.submit_job(config, test_file_path).await
     }
 
+    async fn wait_for_job(batch_client: &BatchClient, job_id: &str) {
+        let mut retries = 0;
+        loop {
+            let get_job_res = batch_client.get_job(job_id).await.unwrap();
+            if get_job_res.job.status == models::job_details::Status::Done {
+                return;
+            } else if get_job_res.job.status != models::job_details::Status::Running {
+                panic!("Job failed");
+            } else {
+                if retries > 6 {
+                    panic!("Job took too long to complete");
+                }
+                retries += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        }
+    }
+
     #[tokio::test]
     async fn test_not_authorised() {
-        let batch_client = BatchClient::new("blah", None).unwrap();
+        let (server, _) = start_server(Default::default()).await;
+        let batch_client = BatchClient::new("blah", Some(server.url())).unwrap();
 
         let job_res = submit_job_util(&batch_client).await;
         match job_res {
             Ok(_) => panic!("Something went wrong with auth"),
             Err(err) => {
-                assert!(err.is::<reqwest::Error>())
+                assert!(matches!(err, BatchError::Api(ApiError { code: 401, .. })))
             }
         }
     }
 
     #[tokio::test]
     async fn test_submit_job_success() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(Default::default()).await;
 
         let job_res = submit_job_util(&batch_client).await.unwrap();
         assert!(!job_res.id.is_empty())
@@ -400,8 +509,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_job() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(Default::default()).await;
 
         let job_res = submit_job_util(&batch_client).await.unwrap();
         let get_job_res = batch_client.get_job(&job_res.id).await.unwrap();
@@ -415,8 +523,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_jobs() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(Default::default()).await;
 
         let _ = submit_job_util(&batch_client).await.unwrap();
         let _ = submit_job_util(&batch_client).await.unwrap();
@@ -427,26 +534,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_json_result() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(std::time::Duration::from_millis(100)).await;
 
         let job_res = submit_job_util(&batch_client).await.unwrap();
-        let mut success = false;
-        let mut retries = 0;
-        while !success {
-            let get_job_res = batch_client.get_job(&job_res.id).await.unwrap();
-            if get_job_res.job.status == models::job_details::Status::Done {
-                success = true
-            } else if get_job_res.job.status != models::job_details::Status::Running {
-                panic!("Job failed");
-            } else {
-                if retries > 6 {
-                    panic!("Job took too long to complete");
-                }
-                retries += 1;
-                std::thread::sleep(std::time::Duration::from_millis(3000));
-            }
-        }
+        let not_ready = batch_client.get_json_result(&job_res.id).await;
+        assert!(matches!(
+            not_ready,
+            Err(BatchError::Api(ApiError { code: 404, .. }))
+        ));
+        wait_for_job(&batch_client, &job_res.id).await;
         let get_result_res = batch_client.get_json_result(&job_res.id).await.unwrap();
         assert!(get_result_res.job.data_name == "example.wav");
         assert!(get_result_res.results.len() != 0)
@@ -454,40 +550,39 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_text_result() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(Default::default()).await;
 
         let job_res = submit_job_util(&batch_client).await.unwrap();
-        let mut success = false;
-        let mut retries = 0;
-        while !success {
-            let get_job_res = batch_client.get_job(&job_res.id).await.unwrap();
-            if get_job_res.job.status == models::job_details::Status::Done {
-                success = true
-            } else if get_job_res.job.status != models::job_details::Status::Running {
-                panic!("Job failed");
-            } else {
-                if retries > 6 {
-                    panic!("Job took too long to complete");
-                }
-                retries += 1;
-                std::thread::sleep(std::time::Duration::from_millis(3000));
-            }
-        }
+        wait_for_job(&batch_client, &job_res.id).await;
         let get_result_res = batch_client.get_text_result(&job_res.id).await.unwrap();
         assert!(get_result_res.len() != 0)
     }
 
     #[tokio::test]
     async fn test_delete_job() {
-        let api_key: String = std::env::var("API_KEY").unwrap();
-        let batch_client = BatchClient::new(&api_key, None).unwrap();
+        let (_server, batch_client) = start_server(std::time::Duration::from_secs(60)).await;
 
         let job_res = submit_job_util(&batch_client).await.unwrap();
+        let locked = batch_client.delete_job(&job_res.id, None).await;
+        assert!(matches!(
+            locked,
+            Err(BatchError::Api(ApiError { code: 423, .. }))
+        ));
         let delete_res = batch_client
             .delete_job(&job_res.id, Some(true))
             .await
             .unwrap();
         assert!(delete_res.job.status == models::job_details::Status::Deleted);
     }
+
+    #[tokio::test]
+    async fn test_injected_failure() {
+        let (server, batch_client) = start_server(Default::default()).await;
+
+        server.inject_failure(503, models::error_response::Error::InternalServerError);
+        let err = submit_job_util(&batch_client).await.unwrap_err();
+        assert!(err.is_retryable());
+        assert!(submit_job_util(&batch_client).await.is_ok());
+        assert_eq!(server.jobs().len(), 1);
+    }
 }
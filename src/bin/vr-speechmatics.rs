@@ -8,22 +8,11 @@ use axum::{
     extract::State
 };
 use axum::extract::ws::WebSocket;
-use futures::stream::{SplitStream, SplitSink};
-use voice_recognition::realtime::models::{self, EndOfStream, StartRecognition};
-use voice_recognition::realtime::ReadMessage;
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
-use futures::{SinkExt, StreamExt};
-use tokio_tungstenite::connect_async;
-use url::Url;
-use base64::{engine::general_purpose, Engine as _};
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use futures::StreamExt;
+use voice_recognition::realtime::{AudioSink, ReadMessage, RealtimeSession, SessionConfig};
 use voice_recognition::config::{get_audio_format, get_transcription_config};
 
-type SpeechmaticsSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
-type SpeechmaticsReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+const SPEECHMATICS_URL: &str = "wss://eu2.rt.speechmatics.com/v2";
 
 struct AppState {
     api_key: String
@@ -38,23 +27,15 @@ async fn main() {
     let app_state = AppState {
         api_key
     };
-    
+
     let app = Router::new().route("/", get(websocket_handler)).with_state(Arc::new(app_state));
     let url = format!("127.0.0.1:{}", port);
-    
+
     let listener = tokio::net::TcpListener::bind(&url).await.unwrap();
     log::info!("Listening on {}", url);
     axum::serve(listener, app).await.unwrap();
 }
 
-struct SpeechmaticsReceiverDrop;
-
-impl Drop for SpeechmaticsReceiverDrop {
-    fn drop(&mut self) {
-        log::info!("SpeechmaticsReceiverDropped");
-    }
-}
-
 /// WebSocket handler
 async fn websocket_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
     ws.on_upgrade(|socket| async {
@@ -65,120 +46,74 @@ async fn websocket_handler(ws: WebSocketUpgrade, State(state): State<Arc<AppStat
 /// Handles the WebSocket connection
 async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     let ( _, mut receiver) = socket.split();
-    let (mut speechmatics_sender, mut speechmatics_receiver) = connect_speechmatics(state.api_key.clone()).await.unwrap();
-    let start_recognition_msg = start_recognition_msg().unwrap();
-
-    let handle1 = tokio::spawn(async move {
-        let _ = SpeechmaticsReceiverDrop;
-        let mut last_seq_no = 0;
-        while let Some(data) = receiver.next().await {
-            if let Ok(data) = data {
-                match data {
-                    axum::extract::ws::Message::Text(utf8_bytes) => {
-                        if utf8_bytes.as_str() == "START_VOICE_RECORDING" {
-                            speechmatics_sender.send(tungstenite::Message::Text(start_recognition_msg.to_string())).await.unwrap();
+    let mut audio_sink: Option<AudioSink> = None;
+
+    while let Some(data) = receiver.next().await {
+        if let Ok(data) = data {
+            match data {
+                axum::extract::ws::Message::Text(utf8_bytes) => {
+                    if utf8_bytes.as_str() == "START_VOICE_RECORDING" && audio_sink.is_none() {
+                        audio_sink = Some(start_session(state.api_key.clone()));
+                    }
+                    if utf8_bytes.as_str() == "STOP_VOICE_RECORDING" {
+                        if let Some(sink) = audio_sink.take() {
+                            let _ = sink.finish();
                         }
-                        if utf8_bytes.as_str() == "STOP_VOICE_RECORDING" {
-                            // let close_msg = end_stream_msg(last_seq_no).unwrap();
-                            // speechmatics_sender.send(tungstenite::Message::Text(close_msg.to_string())).await.unwrap();
+                    }
+                },
+                axum::extract::ws::Message::Binary(bytes) => {
+                    if let Some(sink) = &audio_sink {
+                        if let Err(err) = sink.push(bytes) {
+                            log::error!("{:?}", err);
                         }
-                    },
-                    axum::extract::ws::Message::Binary(bytes) => {
-                        speechmatics_sender.send(tungstenite::Message::binary(bytes.to_vec())).await.unwrap();
-                        last_seq_no += 1;
-                        
-                    },
-                    _ => {}
-                }
+                    }
+                },
+                _ => {}
             }
         }
-      });
+    }
+
+    if let Some(sink) = audio_sink {
+        let _ = sink.finish();
+    }
+}
 
+/// Starts a Speechmatics session in the background, returning the sink to push audio into
+fn start_session(api_key: String) -> AudioSink {
+    let (mut rt_session, mut messages) = RealtimeSession::new(api_key, Some(SPEECHMATICS_URL.to_string())).unwrap();
+    let (audio_sink, audio_reader) = rt_session.audio_sink();
+    let config = SessionConfig::new(
+        Some(get_transcription_config()),
+        None,
+        Some(get_audio_format())
+    );
 
     tokio::spawn(async move {
-        let _ = SpeechmaticsReceiverDrop;
+        if let Err(err) = rt_session.run(config, audio_reader).await {
+            log::error!("{:?}", err);
+        }
+    });
 
-        loop {
-            let value = speechmatics_receiver.next().await;
-            if let Some(value) = value {
-                match value {
-                    Ok(msg) => {
-                        let data = msg.into_data();
-                        let data = serde_json::from_slice::<ReadMessage>(&data);
-                        if let Ok(data) = data {
-                            match data {
-                                ReadMessage::RecognitionStarted(_) => {
-                                    log::info!("RecognitionStarted");
-                                },
-                                ReadMessage::Error(error) => {
-                                    log::error!("Error: {:?}", error);
-                                },
-                                ReadMessage::EndOfTranscript(msg) => {
-                                    log::info!("EndOfTranscript: {:?}", msg);
-                                    handle1.abort();
-                                    break;
-                                },
-                                ReadMessage::AddTranscript(msg) => {
-                                    log::info!("AddTranscript: {:?}", msg.metadata.transcript);
-                                },
-                                _ => {}
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        log::error!("{:?}", err);
-                    },
-                }
+    tokio::spawn(async move {
+        while let Some(message) = messages.recv().await {
+            match message {
+                ReadMessage::RecognitionStarted(_) => {
+                    log::info!("RecognitionStarted");
+                },
+                ReadMessage::Error(error) => {
+                    log::error!("Error: {:?}", error);
+                },
+                ReadMessage::EndOfTranscript(msg) => {
+                    log::info!("EndOfTranscript: {:?}", msg);
+                    break;
+                },
+                ReadMessage::AddTranscript(msg) => {
+                    log::info!("AddTranscript: {:?}", msg.metadata.transcript);
+                },
+                _ => {}
             }
         }
     });
 
+    audio_sink
 }
-
-async fn connect_speechmatics(api_key: String) -> std::result::Result<(SpeechmaticsSender, SpeechmaticsReceiver), ()> {
-    let url = format!("wss://eu2.rt.speechmatics.com/v2?jwt={}", api_key);
-    let authorization = format!("Bearer {}", api_key); 
-    let url = Url::parse(&url).unwrap();
-    let sec_key: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
-    let b64 = general_purpose::STANDARD.encode(sec_key);
-
-    let request = http::Request::builder()
-        .method("GET")
-        .uri(url.as_str())
-        .header("Host", "eu2.rt.speechmatics.com")
-        .header("Authorization", &authorization)
-        .header("Sec-WebSocket-Key", b64)
-        .header("Connection", "keep-alive, Upgrade")
-        .header("Upgrade", "websocket")
-        .header("Sec-WebSocket-Version", "13")
-        .body(())
-        .unwrap();
-
-    let (speechmatics_stream, _) = connect_async(request).await.expect("Failed to connect");
-    Ok(speechmatics_stream.split())
-}
-
-fn start_recognition_msg() -> anyhow::Result<Message> {
-    let message: models::StartRecognition = StartRecognition::new(
-        get_audio_format(), 
-        models::start_recognition::Message::StartRecognition, 
-        get_transcription_config()
-    );
-    let serialised_msg = serde_json::to_string(&message)?;
-    let ws_message = Message::from(serialised_msg);
-    Ok(ws_message)
-}
-
-fn end_stream_msg(last_seq_no: i32) -> anyhow::Result<Message> {
-    let message = EndOfStream {
-        last_seq_no,
-        message: models::end_of_stream::Message::EndOfStream,
-    };
-    let serialised_msg = serde_json::to_string(&message)?;
-    let ws_message = Message::from(serialised_msg);
-    Ok(ws_message)
-}
\ No newline at end of file
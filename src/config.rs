@@ -13,6 +13,7 @@ pub fn get_transcription_config() -> TranscriptionConfig {
         additional_vocab: None, 
         punctuation_overrides: None, 
         diarization: None,
+        conversation_config: None,
         enable_entities: None, 
         max_delay_mode: None, 
         speaker_diarization_config: None,
@@ -5,8 +5,13 @@
 #[macro_use]
 extern crate serde;
 
+pub mod audio;
 #[cfg(feature = "batch")]
 pub mod batch;
+#[cfg(any(feature = "realtime", feature = "batch"))]
+pub mod connection;
+#[cfg(any(feature = "realtime", feature = "batch"))]
+pub mod transcript;
 #[cfg(feature = "realtime")]
 pub mod realtime;
 
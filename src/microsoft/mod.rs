@@ -2,11 +2,15 @@ use cognitive_services_speech_sdk_rs::audio::{
     AudioConfig, AudioStreamFormat, PullAudioInputStream, PushAudioInputStream,
 };
 use cognitive_services_speech_sdk_rs::common::ProfanityOption;
+use cognitive_services_speech_sdk_rs::error::Error as SpeechError;
 // use cognitive_services_speech_sdk_rs::ffi::PropertyId_SpeechServiceResponse_PostProcessingOption;
 use cognitive_services_speech_sdk_rs::speech::{SpeechConfig, SpeechRecognizer};
 // use cognitive_services_speech_sdk_rs::ffi::phrase_list_grammar_add_phrase;
 // use cognitive_services_speech_sdk_rs as msspeech;
 use log::*;
+use std::fmt;
+
+use crate::audio::{AudioError, AudioPipeline, SampleFormat, VoiceActivityDetector};
 
 /// Recognizer
 pub fn set_callbacks(speech_recognizer: &mut SpeechRecognizer) {
@@ -42,19 +46,22 @@ pub fn set_callbacks(speech_recognizer: &mut SpeechRecognizer) {
 ///creates speech recognizer from provided audio config and implicit speech config
 /// created from MS subscription key hardcoded in sample file
 pub fn speech_recognizer_from_audio_cfg(audio_config: AudioConfig, ms_config: MsConfig) -> SpeechRecognizer {
+    try_speech_recognizer_from_audio_cfg(audio_config, ms_config).unwrap()
+}
+
+/// same as speech_recognizer_from_audio_cfg, but returns the SDK error rather than panicking
+pub fn try_speech_recognizer_from_audio_cfg(audio_config: AudioConfig, ms_config: MsConfig) -> cognitive_services_speech_sdk_rs::error::Result<SpeechRecognizer> {
     let mut speech_config = SpeechConfig::from_subscription(
         ms_config.ms_subscription_key,
         ms_config.ms_service_region,
-    )
-    .unwrap();
-    speech_config.set_property(cognitive_services_speech_sdk_rs::common::PropertyId::SpeechServiceResponsePostProcessingOption,"TrueText".to_string()).unwrap();
+    )?;
+    speech_config.set_property(cognitive_services_speech_sdk_rs::common::PropertyId::SpeechServiceResponsePostProcessingOption,"TrueText".to_string())?;
     // let phrase_list = PhraseListGrammar.FromRecognizer(recognizer);
 
-    speech_config.enable_dictation().unwrap();
-    speech_config.set_profanity_option(ProfanityOption::Removed).unwrap();
+    speech_config.enable_dictation()?;
+    speech_config.set_profanity_option(ProfanityOption::Removed)?;
 
-    let speech_recognizer = SpeechRecognizer::from_config(speech_config, audio_config).unwrap();
-    speech_recognizer
+    SpeechRecognizer::from_config(speech_config, audio_config)
 }
 
 /// MsConfig
@@ -74,6 +81,116 @@ pub fn speech_recognizer_from_push_stream(ms_config: MsConfig) -> (SpeechRecogni
     (speech_recognizer_from_audio_cfg(audio_config, ms_config), push_stream)
 }
 
+/// creates speech recognizer from push input stream and MS speech subscription key,
+/// with the audio pushed converted by the pipeline, e.g. from 48 kHz stereo browser audio or 8 kHz mu-law or A-law telephony audio
+/// the pipeline must output s16 audio, as that is what the push stream takes, otherwise PipelineError::Audio is returned
+/// returns recognizer and also push stream so that data push can be initiated
+pub fn speech_recognizer_from_pipeline(ms_config: MsConfig, pipeline: AudioPipeline) -> Result<(SpeechRecognizer, PipelinePushStream), PipelineError> {
+    let output = pipeline.output_format();
+    if output.sample_format != SampleFormat::S16 {
+        return Err(AudioError::Unsupported(format!(
+            "pushing {:?} audio, as the push stream takes s16",
+            output.sample_format
+        ))
+        .into());
+    }
+    let wave_format = AudioStreamFormat::get_wave_format_pcm(output.sample_rate, Some(16), Some(output.channels as u8))?;
+    let stream = PushAudioInputStream::create_push_stream_from_format(wave_format)?;
+    let audio_config = AudioConfig::from_stream_input(&stream)?;
+    let speech_recognizer = try_speech_recognizer_from_audio_cfg(audio_config, ms_config)?;
+    Ok((speech_recognizer, PipelinePushStream { stream, pipeline, vad: None }))
+}
+
+/// error creating a speech recognizer for an AudioPipeline
+#[derive(Debug)]
+pub enum PipelineError {
+    /// the pipeline does not output audio the push stream takes
+    Audio(AudioError),
+    /// the speech SDK failed to create the push stream or the recognizer
+    Speech(SpeechError),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Audio(err) => write!(f, "{}", err),
+            PipelineError::Speech(err) => write!(f, "Speech SDK error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PipelineError::Audio(err) => Some(err),
+            PipelineError::Speech(err) => Some(err),
+        }
+    }
+}
+
+impl From<AudioError> for PipelineError {
+    fn from(err: AudioError) -> Self {
+        PipelineError::Audio(err)
+    }
+}
+
+impl From<SpeechError> for PipelineError {
+    fn from(err: SpeechError) -> Self {
+        PipelineError::Speech(err)
+    }
+}
+
+/// push stream which converts the audio written to it with an AudioPipeline
+pub struct PipelinePushStream {
+    stream: PushAudioInputStream,
+    pipeline: AudioPipeline,
+    vad: Option<VoiceActivityDetector>,
+}
+
+impl PipelinePushStream {
+    /// gates the converted audio with a voice activity detector, so silence is not pushed
+    /// the detector must take the output format of the pipeline, otherwise AudioError::Unsupported is returned,
+    /// and its timeline maps result offsets back onto the original audio
+    pub fn set_vad(&mut self, vad: Option<VoiceActivityDetector>) -> Result<(), AudioError> {
+        if let Some(vad) = &vad {
+            if vad.format() != self.pipeline.output_format() {
+                return Err(AudioError::Unsupported(format!(
+                    "a detector for {:?} audio on a pipeline which outputs {:?}",
+                    vad.format(),
+                    self.pipeline.output_format()
+                )));
+            }
+        }
+        self.vad = vad;
+        Ok(())
+    }
+
+    /// converts a chunk of audio and writes it to the push stream
+    pub fn write(&mut self, buffer: impl AsRef<[u8]>) -> cognitive_services_speech_sdk_rs::error::Result<()> {
+        let mut converted = self.pipeline.process(buffer.as_ref());
+        if let Some(vad) = self.vad.as_mut() {
+            converted = vad.process(&converted);
+        }
+        if converted.is_empty() {
+            return Ok(());
+        }
+        self.stream.write(converted)
+    }
+
+    /// writes the audio held back by the pipeline and closes the push stream
+    pub fn close_stream(&mut self) -> cognitive_services_speech_sdk_rs::error::Result<()> {
+        let mut tail = self.pipeline.flush();
+        if let Some(vad) = self.vad.as_mut() {
+            tail = vad.process(&tail);
+            tail.extend(vad.flush());
+        }
+        if !tail.is_empty() {
+            self.stream.write(tail)?;
+        }
+        self.stream.close_stream()
+    }
+}
+
 /// creates speech recognizer from pull input stream and MS speech subscription key
 /// returns recognizer and also pull stream so that data push can be initiated
 pub fn speech_recognizer_from_pull_stream(ms_config: MsConfig) -> (SpeechRecognizer, PullAudioInputStream) {
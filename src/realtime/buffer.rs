@@ -0,0 +1,204 @@
+//! Bounded delivery of server messages, used when a session is consumed as a Stream through RealtimeSession::run_stream.
+use log::warn;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+
+use super::error::Result;
+use super::{ReadMessage, RealtimeError};
+
+/// What to do when a message arrives from the server and the buffer of a message stream is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Stop reading from the server until the consumer catches up. No messages are lost.
+    #[default]
+    Block,
+    /// Drop partial transcripts and translations to make room. Partials are dropped when they arrive at a full buffer,
+    /// and queued partials are dropped to make room for finals. If the buffer only holds finals, this blocks like Block.
+    DropPartials,
+    /// End the session with an error.
+    Fail,
+}
+
+/// Configures the buffer between the session and the consumer of RealtimeSession::run_stream.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageBuffer {
+    /// The maximum number of messages waiting to be consumed.
+    pub capacity: usize,
+    /// What to do when a message arrives and the buffer is full.
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for MessageBuffer {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            overflow: OverflowPolicy::Block,
+        }
+    }
+}
+
+/// Where a running session delivers the messages it reads from the server
+pub(crate) enum MessageSender {
+    Unbounded(UnboundedSender<ReadMessage>),
+    Bounded(std::sync::Arc<MessageQueue>),
+}
+
+impl MessageSender {
+    pub(crate) async fn send(&self, message: ReadMessage) -> Result<()> {
+        match self {
+            MessageSender::Unbounded(sender) => sender.send(message).map_err(|_| {
+                RealtimeError::Closed(
+                    "The receiver of the session messages has been dropped".to_owned(),
+                )
+            }),
+            MessageSender::Bounded(queue) => queue.push(message).await,
+        }
+    }
+}
+
+fn is_partial(message: &ReadMessage) -> bool {
+    matches!(
+        message,
+        ReadMessage::AddPartialTranscript(_) | ReadMessage::AddPartialTranslation(_)
+    )
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Result<ReadMessage>>,
+    closed: bool,
+}
+
+/// A single producer, single consumer queue of messages which applies an OverflowPolicy once it is full
+pub(crate) struct MessageQueue {
+    buffer: MessageBuffer,
+    state: Mutex<QueueState>,
+    readable: Notify,
+    writable: Notify,
+}
+
+impl MessageQueue {
+    pub(crate) fn new(buffer: MessageBuffer) -> Self {
+        Self {
+            buffer,
+            state: Mutex::new(QueueState::default()),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    async fn push(&self, message: ReadMessage) -> Result<()> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.items.len() < self.buffer.capacity.max(1) {
+                    state.items.push_back(Ok(message));
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+                match self.buffer.overflow {
+                    OverflowPolicy::Block => (),
+                    OverflowPolicy::Fail => {
+                        return Err(RealtimeError::BufferFull);
+                    }
+                    OverflowPolicy::DropPartials => {
+                        if is_partial(&message) {
+                            warn!("The message buffer is full, dropping a partial");
+                            return Ok(());
+                        }
+                        let queued_partial = state
+                            .items
+                            .iter()
+                            .position(|item| matches!(item, Ok(queued) if is_partial(queued)));
+                        if let Some(position) = queued_partial {
+                            warn!("The message buffer is full, dropping a queued partial");
+                            state.items.remove(position);
+                            state.items.push_back(Ok(message));
+                            self.readable.notify_one();
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Waits for the next message, returning None once the queue is closed and empty
+    pub(crate) async fn pop(&self) -> Option<Result<ReadMessage>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    self.writable.notify_one();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Closes the queue once the session has finished, with the error it finished with, if any
+    pub(crate) fn close(&self, err: Option<RealtimeError>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = err {
+            state.items.push_back(Err(err));
+        }
+        state.closed = true;
+        self.readable.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime::models;
+
+    #[tokio::test]
+    async fn test_drop_partials_keeps_finals() {
+        let queue = MessageQueue::new(MessageBuffer {
+            capacity: 2,
+            overflow: OverflowPolicy::DropPartials,
+        });
+        let partial = ReadMessage::AddPartialTranscript(Default::default());
+        let last_final = ReadMessage::AddTranscript(models::AddTranscript {
+            format: Some("2.9".to_owned()),
+            ..Default::default()
+        });
+        queue.push(partial.clone()).await.unwrap();
+        queue
+            .push(ReadMessage::AddTranscript(Default::default()))
+            .await
+            .unwrap();
+        queue.push(partial).await.unwrap();
+        queue.push(last_final).await.unwrap();
+        queue.close(None);
+
+        let mut received = vec![];
+        while let Some(item) = queue.pop().await {
+            received.push(item.unwrap());
+        }
+        assert_eq!(received.len(), 2);
+        assert!(received
+            .iter()
+            .all(|message| matches!(message, ReadMessage::AddTranscript(_))));
+    }
+
+    #[tokio::test]
+    async fn test_fail_on_overflow() {
+        let queue = MessageQueue::new(MessageBuffer {
+            capacity: 1,
+            overflow: OverflowPolicy::Fail,
+        });
+        let partial = ReadMessage::AddPartialTranscript(Default::default());
+        queue.push(partial.clone()).await.unwrap();
+        assert!(matches!(
+            queue.push(partial).await,
+            Err(RealtimeError::BufferFull)
+        ));
+    }
+}
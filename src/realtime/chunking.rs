@@ -0,0 +1,123 @@
+//! Splits the audio read for a session into the chunks sent as AddAudio messages.
+use super::models;
+
+/// How much audio is read at a time when no chunk duration is set
+const DEFAULT_CHUNK_LEN: usize = 8192;
+
+/// Collects audio from the reader into chunks which hold only whole sample frames.
+///
+/// With a chunk duration set, every chunk holds exactly that much audio, so the first chunk is aligned and every later
+/// one starts on a multiple of the duration, except for the last one and any cut short by ForceEndOfUtterance.
+/// Otherwise as much audio is sent as each read returns.
+/// It lasts for a whole call to run, so audio read before a reconnect is not lost.
+pub(crate) struct Chunker {
+    /// Whether the reader has ended, after which only EndOfStream is sent on any new connection
+    pub(crate) reader_finished: bool,
+    buffer: Vec<u8>,
+    filled: usize,
+    /// Whether chunks are only sent once the buffer is full
+    fixed: bool,
+    frame_len: usize,
+}
+
+impl Chunker {
+    /// Creates a chunker for the audio format, returning None if a chunk duration is set which the format does not allow
+    pub(crate) fn new(
+        audio_format: Option<&models::AudioFormat>,
+        chunk_duration_ms: Option<u32>,
+    ) -> Option<Self> {
+        let frame_len = audio_format
+            .and_then(|format| format.bytes_per_sample())
+            .unwrap_or(1) as usize;
+        let (chunk_len, fixed) = match chunk_duration_ms {
+            Some(chunk_duration_ms) => {
+                let bytes_per_second = audio_format?.bytes_per_second()? as u64;
+                let frames = bytes_per_second * chunk_duration_ms as u64 / 1000 / frame_len as u64;
+                if frames == 0 {
+                    return None;
+                }
+                (frames as usize * frame_len, true)
+            }
+            // reads are rounded down to whole frames, so the buffer only needs to hold at least one
+            None => (DEFAULT_CHUNK_LEN.max(frame_len), false),
+        };
+        Some(Self {
+            reader_finished: false,
+            buffer: vec![0; chunk_len],
+            filled: 0,
+            fixed,
+            frame_len,
+        })
+    }
+
+    /// The part of the buffer the next read goes into
+    pub(crate) fn space(&mut self) -> &mut [u8] {
+        &mut self.buffer[self.filled..]
+    }
+
+    /// Takes note of the bytes read into space, returning the next chunk if one is ready
+    pub(crate) fn fill(&mut self, len: usize) -> Option<Vec<u8>> {
+        self.filled += len;
+        let ready = if self.fixed {
+            if self.filled < self.buffer.len() {
+                return None;
+            }
+            self.filled
+        } else {
+            self.filled / self.frame_len * self.frame_len
+        };
+        self.take(ready)
+    }
+
+    /// Returns the whole frames buffered so far, keeping any partial frame for the next chunk
+    pub(crate) fn flush(&mut self) -> Option<Vec<u8>> {
+        self.take(self.filled / self.frame_len * self.frame_len)
+    }
+
+    /// Returns the whole frames left once the reader has ended, dropping any partial frame
+    pub(crate) fn finish(&mut self) -> Option<Vec<u8>> {
+        let chunk = self.flush();
+        self.filled = 0;
+        chunk
+    }
+
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if len == 0 {
+            return None;
+        }
+        let chunk = self.buffer[..len].to_vec();
+        self.buffer.copy_within(len..self.filled, 0);
+        self.filled -= len;
+        Some(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_hold_whole_frames() {
+        let mut format = models::AudioFormat::new(models::audio_format::Type::Raw);
+        format.encoding = Some(models::audio_format::Encoding::PcmF32le);
+        format.sample_rate = Some(16000);
+
+        // 20ms of f32 audio at 16kHz is 1280 bytes, however the reads are split
+        let mut chunker = Chunker::new(Some(&format), Some(20)).unwrap();
+        assert_eq!(chunker.space().len(), 1280);
+        assert_eq!(chunker.fill(1000), None);
+        assert_eq!(chunker.fill(280).map(|chunk| chunk.len()), Some(1280));
+        assert_eq!(chunker.fill(7), None);
+        assert_eq!(chunker.flush().map(|chunk| chunk.len()), Some(4));
+        assert_eq!(chunker.fill(2), None);
+        assert_eq!(chunker.finish().map(|chunk| chunk.len()), Some(4));
+        assert_eq!(chunker.finish(), None);
+
+        // without a chunk duration, reads are sent as they are, less any partial frame
+        let mut chunker = Chunker::new(Some(&format), None).unwrap();
+        assert_eq!(chunker.fill(1001).map(|chunk| chunk.len()), Some(1000));
+        assert_eq!(chunker.fill(3).map(|chunk| chunk.len()), Some(4));
+
+        assert!(Chunker::new(None, Some(20)).is_none());
+    }
+}
@@ -0,0 +1,270 @@
+//! Handles for interacting with a realtime session while it is running, e.g. to update its config on the fly.
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc::UnboundedSender, oneshot, watch};
+
+use super::error::Result;
+use super::stats::StatsCollector;
+use super::{models, Lag, RealtimeError, ServerError, SessionStats};
+
+/// Internal messages passed from a SessionControl handle to the running session
+pub(crate) enum Command {
+    SetRecognitionConfig(models::TranscriptionConfig, oneshot::Sender<Result<()>>),
+    ForceEndOfUtterance(oneshot::Sender<Result<()>>),
+    Stop(Duration, oneshot::Sender<Result<()>>),
+}
+
+impl Command {
+    /// Fails a command which no run is going to handle
+    pub(crate) fn reject(self) {
+        let ack_sender = match self {
+            Command::SetRecognitionConfig(_, ack_sender)
+            | Command::ForceEndOfUtterance(ack_sender)
+            | Command::Stop(_, ack_sender) => ack_sender,
+        };
+        let _ = ack_sender.send(Err(not_running()));
+    }
+}
+
+/// Where commands are sent, which is only set while run is in progress
+pub(crate) type CommandSlot = Arc<Mutex<Option<UnboundedSender<Command>>>>;
+
+/// A cloneable handle to a RealtimeSession, obtained through RealtimeSession::control.
+///
+/// It can be moved into another task and used to talk to the session while run is in progress.
+/// Commands issued while run is not in progress fail straight away with RealtimeError::Closed.
+#[derive(Clone, Debug)]
+pub struct SessionControl {
+    commands: CommandSlot,
+    lag: watch::Receiver<Lag>,
+    stats: Arc<Mutex<StatsCollector>>,
+    abort: Arc<watch::Sender<bool>>,
+}
+
+impl SessionControl {
+    pub(crate) fn new(
+        commands: CommandSlot,
+        lag: watch::Receiver<Lag>,
+        stats: Arc<Mutex<StatsCollector>>,
+        abort: Arc<watch::Sender<bool>>,
+    ) -> Self {
+        Self {
+            commands,
+            lag,
+            stats,
+            abort,
+        }
+    }
+
+    /// Returns how much audio has been sent to the server over the current connection without being acknowledged yet.
+    pub fn lag(&self) -> Lag {
+        *self.lag.borrow()
+    }
+
+    /// Returns the latency and throughput metrics of the current or last run of the session.
+    pub fn stats(&self) -> SessionStats {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    /// Sends a SetRecognitionConfig message to the server, replacing the transcription config of the running session.
+    ///
+    /// Only the fields the server allows to be changed mid-session may differ from the current config:
+    /// additional_vocab, max_delay, max_delay_mode, enable_partials and punctuation_overrides.
+    ///
+    /// The returned future resolves once the server has acknowledged the update, which is
+    /// when it has processed audio sent after the update, or when the session has ended cleanly.
+    ///
+    /// # Errors
+    ///
+    /// This function can error if:
+    ///     - the new config changes a field that is fixed for the lifetime of the session
+    ///     - the server rejects the new config with an Error message
+    ///     - the session ends before the update is acknowledged
+    pub async fn set_recognition_config(&self, config: models::TranscriptionConfig) -> Result<()> {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        self.send(Command::SetRecognitionConfig(config, ack_sender))?;
+        ack_receiver.await.map_err(|_| session_ended())?
+    }
+
+    /// Sends a ForceEndOfUtterance message to the server, which finalises the current utterance straight away
+    /// rather than waiting for the end_of_utterance_silence_trigger set in the conversation_config.
+    ///
+    /// The server replies with any outstanding AddTranscript messages followed by an EndOfUtterance message.
+    /// The returned future resolves once the message has been sent.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if the session ends before the message is sent.
+    pub async fn force_end_of_utterance(&self) -> Result<()> {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        self.send(Command::ForceEndOfUtterance(ack_sender))?;
+        ack_receiver.await.map_err(|_| session_ended())?
+    }
+
+    /// Ends the session gracefully, as if the audio source had run out.
+    ///
+    /// No more audio is read. The session sends EndOfStream, waits up to timeout for the server to send
+    /// the remaining transcripts and EndOfTranscript, and then closes the websocket. If EndOfTranscript
+    /// does not arrive in time, run returns RealtimeError::Timeout.
+    ///
+    /// The returned future resolves once run has finished.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if the session did not finish cleanly, e.g. because the deadline passed,
+    /// or if the session ended before the stop request was handled.
+    pub async fn stop(&self, timeout: Duration) -> Result<()> {
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        self.send(Command::Stop(timeout, ack_sender))?;
+        ack_receiver.await.map_err(|_| session_ended())?
+    }
+
+    /// Tears the session down immediately, without sending EndOfStream or waiting for the outstanding transcripts.
+    ///
+    /// run returns RealtimeError::Aborted straight away, including if it is connecting or waiting to reconnect.
+    /// If run is not in progress, this does nothing.
+    pub fn abort(&self) {
+        self.abort.send_replace(true);
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        match self.commands.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(command).map_err(|_| session_ended()),
+            None => Err(not_running()),
+        }
+    }
+}
+
+fn not_running() -> RealtimeError {
+    RealtimeError::Closed("The realtime session is not running".to_owned())
+}
+
+fn session_ended() -> RealtimeError {
+    RealtimeError::Closed("The realtime session ended before the command completed".to_owned())
+}
+
+/// Checks that an updated config only changes the fields which the server permits to change mid-session.
+pub(crate) fn validate_config_update(
+    current: &models::TranscriptionConfig,
+    update: &models::TranscriptionConfig,
+) -> Result<()> {
+    // any field not copied over here is fixed for the lifetime of the session
+    let mut fixed = update.clone();
+    fixed.additional_vocab = current.additional_vocab.clone();
+    fixed.max_delay = current.max_delay;
+    fixed.max_delay_mode = current.max_delay_mode;
+    fixed.enable_partials = current.enable_partials;
+    fixed.punctuation_overrides = current.punctuation_overrides.clone();
+    if fixed == *current {
+        return Ok(());
+    }
+    let current = serde_json::to_value(current)?;
+    let fixed = serde_json::to_value(&fixed)?;
+    let (Some(current), Some(fixed)) = (current.as_object(), fixed.as_object()) else {
+        return Err(RealtimeError::Config(
+            "The transcription config cannot be changed mid-session".to_owned(),
+        ));
+    };
+    let fixed_fields: BTreeSet<&str> = current
+        .keys()
+        .chain(fixed.keys())
+        .filter(|field| current.get(*field) != fixed.get(*field))
+        .map(String::as_str)
+        .collect();
+    Err(RealtimeError::Config(format!(
+        "The following fields cannot be changed mid-session: {}",
+        fixed_fields.into_iter().collect::<Vec<_>>().join(", ")
+    )))
+}
+
+/// Config updates which have been sent to the server but not yet acknowledged.
+///
+/// The server does not reply to SetRecognitionConfig directly. Messages are processed in order, so an
+/// update counts as acknowledged once an AudioAdded arrives for audio sent after it.
+#[derive(Default)]
+pub(crate) struct PendingUpdates {
+    updates: Vec<(i32, oneshot::Sender<Result<()>>)>,
+}
+
+impl PendingUpdates {
+    /// Registers an update sent after the audio chunk with sequence number last_seq_no
+    pub(crate) fn push(&mut self, last_seq_no: i32, ack_sender: oneshot::Sender<Result<()>>) {
+        self.updates.push((last_seq_no, ack_sender));
+    }
+
+    /// Resolves every update which was sent before the audio chunk with sequence number seq_no
+    pub(crate) fn acknowledge(&mut self, seq_no: i32) {
+        let (acked, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.updates)
+            .into_iter()
+            .partition(|(last_seq_no, _)| *last_seq_no < seq_no);
+        self.updates = pending;
+        for (_, ack_sender) in acked {
+            let _ = ack_sender.send(Ok(()));
+        }
+    }
+
+    /// Resolves every outstanding update, e.g. once EndOfTranscript has been received
+    pub(crate) fn acknowledge_all(&mut self) {
+        for (_, ack_sender) in self.updates.drain(..) {
+            let _ = ack_sender.send(Ok(()));
+        }
+    }
+
+    /// Fails every outstanding update with the error sent by the server
+    pub(crate) fn fail_all(&mut self, err: &ServerError) {
+        for (_, ack_sender) in self.updates.drain(..) {
+            let _ = ack_sender.send(Err(RealtimeError::Server(err.clone())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_config_update() {
+        let current = models::TranscriptionConfig::new("en".to_owned());
+
+        let mut update = current.clone();
+        update.max_delay = Some(1.5);
+        update.enable_partials = Some(true);
+        assert!(validate_config_update(&current, &update).is_ok());
+
+        update.language = "de".to_owned();
+        update.domain = Some("finance".to_owned());
+        let err = validate_config_update(&current, &update).unwrap_err();
+        assert!(err.to_string().contains("domain, language"));
+
+        // fields without a check of their own are fixed too
+        let mut update = current.clone();
+        update.conversation_config = Some(Box::default());
+        let err = validate_config_update(&current, &update).unwrap_err();
+        assert!(err.to_string().ends_with(": conversation_config"));
+    }
+
+    #[tokio::test]
+    async fn test_pending_updates_acknowledge() {
+        let mut pending = PendingUpdates::default();
+        let (first_sender, first_receiver) = oneshot::channel();
+        let (second_sender, mut second_receiver) = oneshot::channel();
+        pending.push(3, first_sender);
+        pending.push(5, second_sender);
+
+        pending.acknowledge(4);
+        assert!(first_receiver.await.unwrap().is_ok());
+        assert!(second_receiver.try_recv().is_err());
+
+        pending.fail_all(&ServerError {
+            error_type: models::error::Type::InvalidConfig,
+            code: None,
+            seq_no: None,
+            reason: "invalid_config".to_owned(),
+        });
+        assert!(matches!(
+            second_receiver.await.unwrap(),
+            Err(RealtimeError::Server(_))
+        ));
+    }
+}
@@ -0,0 +1,278 @@
+//! The error type returned by realtime sessions, so callers can tell server errors, dropped connections and misconfiguration apart.
+use std::fmt;
+
+use tokio_tungstenite::tungstenite;
+
+use super::models;
+
+/// Shorthand for results whose error is a RealtimeError
+pub type Result<T, E = RealtimeError> = std::result::Result<T, E>;
+
+/// An Error message sent by the server, which ends the session.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerError {
+    /// The type of the error, e.g. invalid_audio_type or insufficient_funds
+    pub error_type: models::error::Type,
+    /// The error code, if the server sent one
+    pub code: Option<i32>,
+    /// The sequence number of the audio chunk the error relates to, if any
+    pub seq_no: Option<i32>,
+    /// A human readable description of the error
+    pub reason: String,
+}
+
+impl ServerError {
+    /// Returns true if the session might succeed if it is started again, as opposed to e.g. the config or the account being at fault
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.error_type,
+            models::error::Type::JobError | models::error::Type::UnknownError
+        )
+    }
+}
+
+impl From<&models::Error> for ServerError {
+    fn from(err: &models::Error) -> Self {
+        Self {
+            error_type: err.type_value,
+            code: err.code,
+            seq_no: err.seq_no,
+            reason: err.reason.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.error_type)?;
+        if let Some(code) = self.code {
+            write!(f, " ({})", code)?;
+        }
+        write!(f, ": {}", self.reason)?;
+        if let Some(seq_no) = self.seq_no {
+            write!(f, " at seq_no {}", seq_no)?;
+        }
+        Ok(())
+    }
+}
+
+/// The stage of a session which took too long, as set out in RealtimeOptions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimeoutKind {
+    /// Connecting to the server, limited by RealtimeOptions::connect_timeout
+    Connect,
+    /// Waiting for RecognitionStarted, limited by RealtimeOptions::start_timeout
+    RecognitionStarted,
+    /// Waiting for the next frame from the server, limited by RealtimeOptions::idle_timeout
+    Idle,
+    /// The whole session, limited by RealtimeOptions::max_duration
+    MaxDuration,
+    /// Waiting for EndOfTranscript, limited by the timeout passed to SessionControl::stop
+    Stop,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutKind::Connect => write!(f, "could not connect to the server in time"),
+            TimeoutKind::RecognitionStarted => {
+                write!(f, "the server did not send RecognitionStarted in time")
+            }
+            TimeoutKind::Idle => write!(f, "the server stopped sending messages"),
+            TimeoutKind::MaxDuration => write!(f, "the session reached its maximum duration"),
+            TimeoutKind::Stop => write!(
+                f,
+                "the server did not send EndOfTranscript before the stop deadline"
+            ),
+        }
+    }
+}
+
+/// Everything that can go wrong in a realtime session.
+#[derive(Debug)]
+pub enum RealtimeError {
+    /// The server sent an Error message
+    Server(ServerError),
+    /// The connection failed or dropped, e.g. because of a network error or the websocket closing before EndOfTranscript
+    Transport(Box<tungstenite::Error>),
+    /// The TLS connection to the server could not be set up
+    Tls(tungstenite::error::TlsError),
+    /// The websocket handshake was rejected. The status is the HTTP status code of the response, if there was one
+    Handshake {
+        /// The HTTP status code of the response
+        status: Option<u16>,
+        /// A description of what went wrong
+        message: String,
+    },
+    /// Something did not happen in the time allowed
+    Timeout(TimeoutKind),
+    /// The server sent something the client did not expect, e.g. a malformed websocket frame
+    Protocol(String),
+    /// A message could not be serialised or deserialised
+    Serialization(serde_json::Error),
+    /// The session or one of its inputs was configured incorrectly
+    Config(String),
+    /// The session, or the handle used to talk to it, has already finished
+    Closed(String),
+    /// The message buffer of run_stream was full and its OverflowPolicy is Fail
+    BufferFull,
+    /// The session was torn down with SessionControl::abort
+    Aborted,
+}
+
+impl RealtimeError {
+    /// Returns true if the session might succeed if it is started again, e.g. after a dropped connection or a timeout.
+    ///
+    /// Server errors are retryable if the server reported a problem on its side, and handshakes are retryable
+    /// if the server was overloaded or rate limiting, but not if e.g. the API key was rejected.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RealtimeError::Server(err) => err.is_retryable(),
+            RealtimeError::Transport(_) | RealtimeError::Timeout(_) => true,
+            RealtimeError::Handshake {
+                status: Some(status),
+                ..
+            } => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the error means the websocket has gone away, as opposed to e.g. the server rejecting the session
+    pub(crate) fn is_disconnect(&self) -> bool {
+        matches!(self, RealtimeError::Transport(_))
+    }
+
+    /// The error returned when the websocket closes before the session has finished
+    pub(crate) fn connection_closed() -> Self {
+        RealtimeError::Transport(Box::new(tungstenite::Error::ConnectionClosed))
+    }
+}
+
+impl fmt::Display for RealtimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RealtimeError::Server(err) => write!(f, "Received error from server {}", err),
+            RealtimeError::Transport(err) => write!(f, "Connection failed: {}", err),
+            RealtimeError::Tls(err) => write!(f, "TLS error: {}", err),
+            RealtimeError::Handshake {
+                status: Some(status),
+                message,
+            } => write!(
+                f,
+                "Websocket handshake failed with status {}: {}",
+                status, message
+            ),
+            RealtimeError::Handshake {
+                status: None,
+                message,
+            } => write!(f, "Websocket handshake failed: {}", message),
+            RealtimeError::Timeout(kind) => write!(f, "Timed out: {}", kind),
+            RealtimeError::Protocol(message) => write!(f, "Protocol error: {}", message),
+            RealtimeError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            RealtimeError::Config(message) => write!(f, "Invalid config: {}", message),
+            RealtimeError::Closed(message) => write!(f, "{}", message),
+            RealtimeError::BufferFull => write!(
+                f,
+                "The message buffer is full, the consumer is not keeping up with the session"
+            ),
+            RealtimeError::Aborted => write!(f, "The session was aborted"),
+        }
+    }
+}
+
+impl std::error::Error for RealtimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RealtimeError::Transport(err) => Some(err.as_ref()),
+            RealtimeError::Tls(err) => Some(err),
+            RealtimeError::Serialization(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for RealtimeError {
+    fn from(err: tungstenite::Error) -> Self {
+        use tungstenite::error::ProtocolError;
+        match err {
+            tungstenite::Error::Tls(err) => RealtimeError::Tls(err),
+            tungstenite::Error::Http(response) => {
+                let message = response
+                    .body()
+                    .as_ref()
+                    .map(|body| String::from_utf8_lossy(body).into_owned())
+                    .unwrap_or_else(|| "the server rejected the connection".to_owned());
+                RealtimeError::Handshake {
+                    status: Some(response.status().as_u16()),
+                    message,
+                }
+            }
+            tungstenite::Error::HttpFormat(err) => RealtimeError::Handshake {
+                status: None,
+                message: err.to_string(),
+            },
+            tungstenite::Error::Url(err) => RealtimeError::Config(err.to_string()),
+            tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => {
+                RealtimeError::Transport(Box::new(err))
+            }
+            tungstenite::Error::Protocol(_)
+            | tungstenite::Error::Capacity(_)
+            | tungstenite::Error::Utf8 => RealtimeError::Protocol(err.to_string()),
+            err => RealtimeError::Transport(Box::new(err)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RealtimeError {
+    fn from(err: serde_json::Error) -> Self {
+        RealtimeError::Serialization(err)
+    }
+}
+
+impl From<std::io::Error> for RealtimeError {
+    fn from(err: std::io::Error) -> Self {
+        RealtimeError::Transport(Box::new(tungstenite::Error::Io(err)))
+    }
+}
+
+impl From<url::ParseError> for RealtimeError {
+    fn from(err: url::ParseError) -> Self {
+        RealtimeError::Config(format!("invalid url: {}", err))
+    }
+}
+
+impl From<http::Error> for RealtimeError {
+    fn from(err: http::Error) -> Self {
+        RealtimeError::Config(format!("could not build the websocket request: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_retryable() {
+        let mut server_error = ServerError {
+            error_type: models::error::Type::InsufficientFunds,
+            code: Some(4005),
+            seq_no: None,
+            reason: "Quota exceeded".to_owned(),
+        };
+        assert!(!RealtimeError::Server(server_error.clone()).is_retryable());
+        server_error.error_type = models::error::Type::JobError;
+        assert!(RealtimeError::Server(server_error).is_retryable());
+
+        let dropped = RealtimeError::from(tungstenite::Error::Protocol(
+            tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
+        ));
+        assert!(dropped.is_retryable() && dropped.is_disconnect());
+
+        let rejected = RealtimeError::Handshake {
+            status: Some(401),
+            message: "Unauthorized".to_owned(),
+        };
+        assert!(!rejected.is_retryable());
+        assert!(!RealtimeError::Config("bad".to_owned()).is_retryable());
+    }
+}
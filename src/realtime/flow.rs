@@ -0,0 +1,141 @@
+//! Flow control for the audio sent to the server, based on the AudioAdded acknowledgements it sends back.
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Limits how much audio may be in flight, i.e. sent to the server but not yet acknowledged with AudioAdded.
+///
+/// Once either limit is reached, the session stops reading audio until the server catches up.
+/// This prevents the server from rejecting fast input, such as a file, with a buffer_error.
+/// Both limits default to None, which means audio is sent as fast as it can be read.
+/// A chunk can always be sent when nothing is in flight, so a limit of 0 allows one chunk at a time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowControl {
+    /// The maximum number of unacknowledged audio chunks.
+    pub max_chunks: Option<usize>,
+    /// The maximum number of bytes of unacknowledged audio.
+    pub max_bytes: Option<usize>,
+}
+
+/// How far behind the server is with the audio sent to it, as returned by SessionControl::lag.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lag {
+    /// The number of audio chunks sent but not yet acknowledged.
+    pub chunks: usize,
+    /// The number of bytes of audio sent but not yet acknowledged.
+    pub bytes: usize,
+    /// The duration of the audio sent but not yet acknowledged.
+    /// This is only known for raw audio with the encoding and sample rate set.
+    pub seconds: Option<f32>,
+}
+
+/// Tracks the audio chunks sent over the current connection which the server has not yet acknowledged
+pub(crate) struct InFlight {
+    flow_control: FlowControl,
+    bytes_per_second: Option<u32>,
+    chunks: VecDeque<(i32, usize)>,
+    bytes: usize,
+    lag_sender: Arc<watch::Sender<Lag>>,
+}
+
+impl InFlight {
+    pub(crate) fn new(
+        flow_control: FlowControl,
+        bytes_per_second: Option<u32>,
+        lag_sender: Arc<watch::Sender<Lag>>,
+    ) -> Self {
+        let in_flight = Self {
+            flow_control,
+            bytes_per_second,
+            chunks: VecDeque::new(),
+            bytes: 0,
+            lag_sender,
+        };
+        in_flight.publish();
+        in_flight
+    }
+
+    /// Records a chunk which has just been sent with the given sequence number
+    pub(crate) fn sent(&mut self, seq_no: i32, len: usize) {
+        self.chunks.push_back((seq_no, len));
+        self.bytes += len;
+        self.publish();
+    }
+
+    /// Forgets every chunk up to and including the given sequence number
+    pub(crate) fn acknowledge(&mut self, seq_no: i32) {
+        while let Some((chunk_seq_no, len)) = self.chunks.front() {
+            if *chunk_seq_no > seq_no {
+                break;
+            }
+            self.bytes -= len;
+            self.chunks.pop_front();
+        }
+        self.publish();
+    }
+
+    /// Returns true if no more audio should be sent until the server acknowledges some of it
+    pub(crate) fn is_full(&self) -> bool {
+        let chunks_full = self
+            .flow_control
+            .max_chunks
+            .is_some_and(|max_chunks| !self.chunks.is_empty() && self.chunks.len() >= max_chunks);
+        let bytes_full = self
+            .flow_control
+            .max_bytes
+            .is_some_and(|max_bytes| self.bytes > 0 && self.bytes >= max_bytes);
+        chunks_full || bytes_full
+    }
+
+    fn publish(&self) {
+        let lag = Lag {
+            chunks: self.chunks.len(),
+            bytes: self.bytes,
+            seconds: self
+                .bytes_per_second
+                .filter(|bytes_per_second| *bytes_per_second > 0)
+                .map(|bytes_per_second| self.bytes as f32 / bytes_per_second as f32),
+        };
+        self.lag_sender.send_replace(lag);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_window() {
+        let (lag_sender, lag_receiver) = watch::channel(Lag::default());
+        let flow_control = FlowControl {
+            max_chunks: Some(3),
+            max_bytes: None,
+        };
+        let mut in_flight = InFlight::new(flow_control, Some(32000), Arc::new(lag_sender));
+
+        for seq_no in 1..=3 {
+            assert!(!in_flight.is_full());
+            in_flight.sent(seq_no, 3200);
+        }
+        assert!(in_flight.is_full());
+        assert_eq!(lag_receiver.borrow().seconds, Some(0.3));
+
+        in_flight.acknowledge(2);
+        assert!(!in_flight.is_full());
+        assert_eq!(lag_receiver.borrow().chunks, 1);
+        assert_eq!(lag_receiver.borrow().bytes, 3200);
+
+        // a window of no chunks still lets one through at a time, rather than never sending any audio
+        let (lag_sender, _) = watch::channel(Lag::default());
+        let flow_control = FlowControl {
+            max_chunks: Some(0),
+            max_bytes: Some(0),
+        };
+        let mut in_flight = InFlight::new(flow_control, None, Arc::new(lag_sender));
+        assert!(!in_flight.is_full());
+        in_flight.sent(1, 3200);
+        assert!(in_flight.is_full());
+        in_flight.acknowledge(1);
+        assert!(!in_flight.is_full());
+    }
+}
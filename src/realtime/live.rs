@@ -0,0 +1,253 @@
+//! A live view of a realtime transcript for captions, reconciling partial transcripts with the finals which replace them.
+//!
+//! Each AddPartialTranscript covers all of the audio since the last AddTranscript, and replaces the partial before it.
+//! Each AddTranscript commits the words up to its end time, replacing the partial words which covered the same audio.
+use super::models::{AddPartialTranscript, AddTranscript};
+use super::ReadMessage;
+use crate::transcript::{self, TranscriptItem};
+
+/// A change to the text of a LiveTranscript, which a UI can apply to its own copy of the text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TranscriptEvent {
+    /// Text was appended to the end
+    Append {
+        /// The text appended
+        text: String,
+    },
+    /// Everything from offset onwards was replaced
+    Replace {
+        /// A byte offset into the text before the change. It always falls within the partial tail, never the stable text
+        offset: usize,
+        /// The text which replaces everything from offset onwards
+        text: String,
+    },
+}
+
+impl TranscriptEvent {
+    /// Applies the change to a copy of the text
+    pub fn apply(&self, text: &mut String) {
+        match self {
+            TranscriptEvent::Append { text: appended } => text.push_str(appended),
+            TranscriptEvent::Replace {
+                offset,
+                text: replacement,
+            } => {
+                text.truncate(*offset);
+                text.push_str(replacement);
+            }
+        }
+    }
+}
+
+/// The text of a realtime transcript as it stands, made up of the committed finals followed by the current partial tail.
+///
+/// The stable text only ever grows, so a UI can render it once and redraw only the partial tail as it changes.
+///
+/// # Example
+///
+/// ```
+/// let mut live = LiveTranscript::new();
+/// while let Some(message) = receive_channel.recv().await {
+///     if let Some(event) = live.update(&message) {
+///         println!("{:?}", event);
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct LiveTranscript {
+    text: String,
+    /// The length of the stable prefix of text
+    committed: usize,
+    /// Whether the last committed item attaches to the next one
+    attach_next: bool,
+    /// The end of the audio covered by the finals, in seconds
+    committed_end: f32,
+    partials: Vec<TranscriptItem>,
+}
+
+impl LiveTranscript {
+    /// Creates an empty transcript
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a message from the session, returning the change to the text if the message is a transcript which changed it
+    pub fn update(&mut self, message: &ReadMessage) -> Option<TranscriptEvent> {
+        match message {
+            ReadMessage::AddPartialTranscript(partial) => self.add_partial(partial),
+            ReadMessage::AddTranscript(transcript) => self.add_final(transcript),
+            _ => None,
+        }
+    }
+
+    /// Replaces the partial tail
+    pub fn add_partial(&mut self, partial: &AddPartialTranscript) -> Option<TranscriptEvent> {
+        let old_tail = self.text.split_off(self.committed);
+        // a partial which arrives late may cover audio which has already been committed
+        let committed_end = self.committed_end;
+        self.partials = partial
+            .results
+            .iter()
+            .map(TranscriptItem::from)
+            .filter(|item| item.start_time >= committed_end)
+            .collect();
+        self.render_partials();
+        self.diff(self.committed, &old_tail)
+    }
+
+    /// Commits a final, dropping the partial words it replaces. Partial words after its end time stay in the tail
+    pub fn add_final(&mut self, transcript: &AddTranscript) -> Option<TranscriptEvent> {
+        let base = self.committed;
+        let old_tail = self.text.split_off(base);
+        for item in transcript.results.iter().map(TranscriptItem::from) {
+            transcript::append(&mut self.text, &mut self.attach_next, &item);
+        }
+        self.committed = self.text.len();
+        self.committed_end = self.committed_end.max(transcript.metadata.end_time);
+        let committed_end = self.committed_end;
+        self.partials
+            .retain(|item| item.start_time >= committed_end);
+        self.render_partials();
+        self.diff(base, &old_tail)
+    }
+
+    /// The whole text, the stable text followed by the partial tail
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The text of the committed finals, which will not change
+    pub fn stable_text(&self) -> &str {
+        &self.text[..self.committed]
+    }
+
+    /// The text of the current partial, including the space which separates it from the stable text
+    pub fn partial_text(&self) -> &str {
+        &self.text[self.committed..]
+    }
+
+    fn render_partials(&mut self) {
+        let mut attach_next = self.attach_next;
+        for item in &self.partials {
+            transcript::append(&mut self.text, &mut attach_next, item);
+        }
+    }
+
+    /// Compares the text from base onwards with what it was before the change
+    fn diff(&self, base: usize, old: &str) -> Option<TranscriptEvent> {
+        let new = &self.text[base..];
+        let common = old
+            .char_indices()
+            .zip(new.chars())
+            .find(|((_, old_char), new_char)| old_char != new_char)
+            .map(|((index, _), _)| index)
+            .unwrap_or_else(|| old.len().min(new.len()));
+        if common == old.len() && common == new.len() {
+            None
+        } else if common == old.len() {
+            Some(TranscriptEvent::Append {
+                text: new[common..].to_owned(),
+            })
+        } else {
+            Some(TranscriptEvent::Replace {
+                offset: base + common,
+                text: new[common..].to_owned(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime::models::{
+        add_partial_transcript, add_transcript, recognition_result, RecognitionAlternative,
+        RecognitionMetadata, RecognitionResult,
+    };
+
+    /// Results for each word, a tenth of a second long each, starting at start
+    fn results(start: f32, words: &[&str]) -> Vec<RecognitionResult> {
+        words
+            .iter()
+            .enumerate()
+            .map(|(index, word)| {
+                let begin = start + index as f32 * 0.1;
+                let type_value = if *word == "." {
+                    recognition_result::Type::Punctuation
+                } else {
+                    recognition_result::Type::Word
+                };
+                let mut result = RecognitionResult::new(begin + 0.1, begin, type_value);
+                result.alternatives =
+                    Some(vec![RecognitionAlternative::new(1.0, word.to_string())]);
+                result
+            })
+            .collect()
+    }
+
+    fn partial(start: f32, words: &[&str]) -> ReadMessage {
+        ReadMessage::AddPartialTranscript(AddPartialTranscript::new(
+            add_partial_transcript::Message::AddPartialTranscript,
+            RecognitionMetadata::default(),
+            results(start, words),
+        ))
+    }
+
+    fn final_(start: f32, words: &[&str]) -> ReadMessage {
+        let metadata =
+            RecognitionMetadata::new(start + words.len() as f32 * 0.1, start, String::new());
+        ReadMessage::AddTranscript(AddTranscript::new(
+            add_transcript::Message::AddTranscript,
+            metadata,
+            results(start, words),
+        ))
+    }
+
+    #[test]
+    fn test_partials_and_finals() {
+        let mut live = LiveTranscript::new();
+        let mut rendered = String::new();
+        let mut update = |live: &mut LiveTranscript, message: ReadMessage| {
+            let event = live.update(&message);
+            if let Some(event) = &event {
+                event.apply(&mut rendered);
+            }
+            assert_eq!(rendered, live.text());
+            event
+        };
+
+        assert_eq!(
+            update(&mut live, partial(0.0, &["hello", "wor"])),
+            Some(TranscriptEvent::Append {
+                text: "hello wor".to_owned()
+            })
+        );
+        assert_eq!(
+            update(&mut live, partial(0.0, &["hello", "word"])),
+            Some(TranscriptEvent::Append {
+                text: "d".to_owned()
+            })
+        );
+        assert_eq!(
+            update(&mut live, partial(0.0, &["hello", "world", "how"])),
+            Some(TranscriptEvent::Replace {
+                offset: 9,
+                text: "ld how".to_owned()
+            })
+        );
+        // the final covers the first two words, and the partial word after them stays in the tail
+        assert_eq!(
+            update(&mut live, final_(0.0, &["Hello", "world"])),
+            Some(TranscriptEvent::Replace {
+                offset: 0,
+                text: "Hello world how".to_owned()
+            })
+        );
+        assert_eq!(live.stable_text(), "Hello world");
+        assert_eq!(live.partial_text(), " how");
+        assert_eq!(update(&mut live, partial(0.2, &["how"])), None);
+        update(&mut live, final_(0.2, &["how", "are", "you", "."]));
+        assert_eq!(live.text(), "Hello world how are you.");
+        assert_eq!(live.partial_text(), "");
+    }
+}
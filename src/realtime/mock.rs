@@ -0,0 +1,402 @@
+//! A local websocket server which speaks the realtime protocol, so sessions can be tested without an API key.
+//!
+//! The server acknowledges StartRecognition and every chunk of audio, then sends transcripts from a script,
+//! a rule, or both. Errors, warnings and disconnects can be injected into a running session with MockServer::inject.
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message, WebSocketStream};
+
+#[cfg(test)]
+use std::{println as debug, println as warn};
+
+#[cfg(not(test))]
+use log::{debug, warn};
+
+use super::error::Result;
+use super::{models, ReadMessage};
+
+/// Generates the messages to send after a chunk of audio, given the sequence number of the chunk
+pub type MockRule = Arc<dyn Fn(i32) -> Vec<ReadMessage> + Send + Sync>;
+
+/// A message which the server sends once it has received a number of chunks of audio.
+#[derive(Clone, Debug)]
+pub struct ScriptedMessage {
+    /// The number of chunks of audio to wait for. Messages which are not due by EndOfStream are sent before EndOfTranscript
+    pub after_chunks: i32,
+    /// The message to send
+    pub message: ReadMessage,
+}
+
+/// Configures the responses of a MockServer.
+#[derive(Clone, Default)]
+pub struct MockConfig {
+    /// Messages sent once a given number of chunks of audio has been received
+    pub script: Vec<ScriptedMessage>,
+    /// Generates messages after every chunk of audio, e.g. words_rule
+    pub rule: Option<MockRule>,
+    /// If set, this error is sent in place of RecognitionStarted, e.g. to test a rejected API key
+    pub start_error: Option<models::Error>,
+}
+
+/// Something to do to the connections of a running MockServer.
+#[derive(Clone, Debug)]
+pub enum MockAction {
+    /// Sends a message, e.g. an Error or a Warning built with error_message or warning_message
+    Send(ReadMessage),
+    /// Drops the connection without a closing handshake, as if the network had failed
+    Disconnect,
+    /// Closes the connection with a closing handshake
+    Close,
+}
+
+/// What a MockServer has received, across all of its connections.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MockStats {
+    /// The number of websocket connections accepted
+    pub connections: usize,
+    /// The StartRecognition messages received, in order
+    pub start_recognitions: Vec<models::StartRecognition>,
+    /// The number of chunks of audio received
+    pub audio_chunks: usize,
+    /// The number of bytes of audio received
+    pub audio_bytes: usize,
+    /// The SetRecognitionConfig messages received
+    pub config_updates: usize,
+    /// The ForceEndOfUtterance messages received
+    pub forced_end_of_utterances: usize,
+    /// The EndOfStream messages received
+    pub end_of_streams: usize,
+}
+
+/// A realtime server listening on a local port, which is shut down when dropped.
+///
+/// # Example
+///
+/// ```
+/// let server = MockServer::start(MockConfig {
+///     rule: Some(words_rule(&["hello", "world"], 2, 0.5)),
+///     ..Default::default()
+/// })
+/// .await
+/// .unwrap();
+///
+/// let (mut rt_session, mut messages) = RealtimeSession::new("ANY_KEY".to_owned(), Some(server.url())).unwrap();
+/// ```
+pub struct MockServer {
+    addr: SocketAddr,
+    stats: Arc<Mutex<MockStats>>,
+    actions: Arc<Mutex<Vec<UnboundedSender<MockAction>>>>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a server on a free port of localhost
+    pub async fn start(config: MockConfig) -> Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+        let stats = Arc::new(Mutex::new(MockStats::default()));
+        let actions = Arc::new(Mutex::new(vec![]));
+
+        let task_stats = stats.clone();
+        let task_actions = actions.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((tcp_stream, _)) = listener.accept().await {
+                let (action_sender, action_receiver) = unbounded_channel();
+                task_actions.lock().unwrap().push(action_sender);
+                let connection = MockConnection {
+                    config: config.clone(),
+                    stats: task_stats.clone(),
+                    actions: action_receiver,
+                    seq_no: 0,
+                    next_scripted: 0,
+                };
+                tokio::spawn(async move {
+                    if let Err(err) = connection.run(tcp_stream).await {
+                        warn!("Mock connection failed: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            addr,
+            stats,
+            actions,
+            accept_task,
+        })
+    }
+
+    /// The URL to pass to RealtimeSession::new
+    pub fn url(&self) -> String {
+        format!("ws://{}/v2", self.addr)
+    }
+
+    /// Returns everything the server has received so far
+    pub fn stats(&self) -> MockStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Applies the action to every open connection. Returns the number of connections it was applied to
+    pub fn inject(&self, action: MockAction) -> usize {
+        let mut actions = self.actions.lock().unwrap();
+        actions.retain(|sender| sender.send(action.clone()).is_ok());
+        actions.len()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+        // dropping the action senders ends every open connection
+        self.actions.lock().unwrap().clear();
+    }
+}
+
+/// The state of a single connection to a MockServer
+struct MockConnection {
+    config: MockConfig,
+    stats: Arc<Mutex<MockStats>>,
+    actions: UnboundedReceiver<MockAction>,
+    seq_no: i32,
+    next_scripted: usize,
+}
+
+impl MockConnection {
+    async fn run(mut self, tcp_stream: TcpStream) -> Result<()> {
+        let mut websocket = accept_async(tcp_stream).await?;
+        self.stats.lock().unwrap().connections += 1;
+        self.config
+            .script
+            .sort_by_key(|scripted| scripted.after_chunks);
+        loop {
+            select! {
+                message = websocket.next() => match message {
+                    Some(Ok(message)) => {
+                        if !self.handle_message(&mut websocket, message).await? {
+                            return Ok(());
+                        }
+                    }
+                    Some(Err(err)) => return Err(err.into()),
+                    None => return Ok(()),
+                },
+                action = self.actions.recv() => match action {
+                    Some(MockAction::Send(message)) => send(&mut websocket, &message).await?,
+                    Some(MockAction::Disconnect) | None => {
+                        debug!("Mock server dropping the connection");
+                        return Ok(());
+                    }
+                    Some(MockAction::Close) => {
+                        websocket.close(None).await?;
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Responds to a message from the session. Returns false once the connection should end
+    async fn handle_message(
+        &mut self,
+        websocket: &mut WebSocketStream<TcpStream>,
+        message: Message,
+    ) -> Result<bool> {
+        let text = match message {
+            Message::Binary(data) => {
+                self.seq_no += 1;
+                {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.audio_chunks += 1;
+                    stats.audio_bytes += data.len();
+                }
+                let audio_added =
+                    models::AudioAdded::new(models::audio_added::Message::AudioAdded, self.seq_no);
+                send(websocket, &ReadMessage::AudioAdded(audio_added)).await?;
+                if let Some(rule) = self.config.rule.clone() {
+                    for message in rule(self.seq_no) {
+                        send(websocket, &message).await?;
+                    }
+                }
+                self.send_scripted(websocket, Some(self.seq_no)).await?;
+                return Ok(true);
+            }
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(false),
+            _ => return Ok(true),
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&text)?;
+        match value["message"].as_str() {
+            Some("StartRecognition") => {
+                let start = serde_json::from_value(value)?;
+                self.stats.lock().unwrap().start_recognitions.push(start);
+                if let Some(err) = &self.config.start_error {
+                    send(websocket, &ReadMessage::Error(err.clone())).await?;
+                    websocket.close(None).await?;
+                    return Ok(false);
+                }
+                let mut started = models::RecognitionStarted::new(
+                    models::recognition_started::Message::RecognitionStarted,
+                );
+                started.id = Some(format!("mock-{}", self.stats.lock().unwrap().connections));
+                send(websocket, &ReadMessage::RecognitionStarted(started)).await?;
+            }
+            Some("SetRecognitionConfig") => self.stats.lock().unwrap().config_updates += 1,
+            Some("ForceEndOfUtterance") => self.stats.lock().unwrap().forced_end_of_utterances += 1,
+            Some("EndOfStream") => {
+                self.stats.lock().unwrap().end_of_streams += 1;
+                self.send_scripted(websocket, None).await?;
+                let end = models::EndOfTranscript::new(
+                    models::end_of_transcript::Message::EndOfTranscript,
+                );
+                send(websocket, &ReadMessage::EndOfTranscript(end)).await?;
+            }
+            message => warn!("Mock server ignoring message {:?}", message),
+        }
+        Ok(true)
+    }
+
+    /// Sends the scripted messages which are due after the given chunk, or all of the rest if there is no chunk
+    async fn send_scripted(
+        &mut self,
+        websocket: &mut WebSocketStream<TcpStream>,
+        seq_no: Option<i32>,
+    ) -> Result<()> {
+        while let Some(scripted) = self.config.script.get(self.next_scripted) {
+            if seq_no.is_some_and(|seq_no| scripted.after_chunks > seq_no) {
+                break;
+            }
+            send(websocket, &scripted.message).await?;
+            self.next_scripted += 1;
+        }
+        Ok(())
+    }
+}
+
+async fn send(websocket: &mut WebSocketStream<TcpStream>, message: &ReadMessage) -> Result<()> {
+    let text = serde_json::to_string(message)?;
+    websocket.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+/// Builds a final or partial transcript of a single word
+pub fn transcript(word: &str, start_time: f32, end_time: f32, is_final: bool) -> ReadMessage {
+    let metadata = models::RecognitionMetadata::new(end_time, start_time, word.to_owned());
+    let mut result = models::RecognitionResult::new(
+        end_time,
+        start_time,
+        models::recognition_result::Type::Word,
+    );
+    result.alternatives = Some(vec![models::RecognitionAlternative::new(
+        1.0,
+        word.to_owned(),
+    )]);
+    if is_final {
+        ReadMessage::AddTranscript(models::AddTranscript::new(
+            models::add_transcript::Message::AddTranscript,
+            metadata,
+            vec![result],
+        ))
+    } else {
+        ReadMessage::AddPartialTranscript(models::AddPartialTranscript::new(
+            models::add_partial_transcript::Message::AddPartialTranscript,
+            metadata,
+            vec![result],
+        ))
+    }
+}
+
+/// Builds an Error message, to inject with MockAction::Send or to set as MockConfig::start_error
+pub fn error_message(error_type: models::error::Type, reason: &str) -> models::Error {
+    models::Error::new(models::error::Message::Error, reason.to_owned(), error_type)
+}
+
+/// Builds a Warning message, to inject with MockAction::Send
+pub fn warning_message(reason: &str) -> ReadMessage {
+    ReadMessage::Warning(models::Warning::new(
+        models::warning::Message::Warning,
+        reason.to_owned(),
+        models::warning::Type::DurationLimitExceeded,
+    ))
+}
+
+/// A rule which transcribes the words in turn, one every chunks_per_word chunks of audio.
+///
+/// Each chunk gets a partial of the word in progress, and the last chunk of a word gets its final.
+/// Timestamps assume every chunk holds chunk_duration seconds of audio. The words run out after the last one.
+pub fn words_rule(words: &[&str], chunks_per_word: i32, chunk_duration: f32) -> MockRule {
+    let words: Vec<String> = words.iter().map(|word| (*word).to_owned()).collect();
+    let chunks_per_word = chunks_per_word.max(1);
+    Arc::new(move |seq_no| {
+        let index = ((seq_no - 1) / chunks_per_word) as usize;
+        let word = match words.get(index) {
+            Some(word) => word,
+            None => return vec![],
+        };
+        let start_time = index as f32 * chunks_per_word as f32 * chunk_duration;
+        let end_time = seq_no as f32 * chunk_duration;
+        let is_final = seq_no % chunks_per_word == 0;
+        vec![transcript(word, start_time, end_time, is_final)]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime::{RealtimeError, RealtimeSession, SessionConfig};
+
+    #[tokio::test]
+    async fn test_session_against_mock_server() {
+        let server = MockServer::start(MockConfig {
+            rule: Some(words_rule(&["hello", "world"], 2, 0.5)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let (mut rt_session, mut messages) =
+            RealtimeSession::new("key".to_owned(), Some(server.url())).unwrap();
+
+        let audio = std::io::Cursor::new(vec![0u8; 8192 * 4]);
+        rt_session
+            .run(SessionConfig::default(), audio)
+            .await
+            .unwrap();
+
+        let stats = server.stats();
+        assert_eq!(stats.audio_chunks, 4);
+        assert_eq!(stats.end_of_streams, 1);
+        let mut finals = vec![];
+        while let Ok(message) = messages.try_recv() {
+            if let ReadMessage::AddTranscript(transcript) = message {
+                finals.push(transcript.metadata.transcript);
+            }
+        }
+        assert_eq!(finals, vec!["hello", "world"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_start_error() {
+        let server = MockServer::start(MockConfig {
+            start_error: Some(error_message(models::error::Type::NotAuthorised, "bad key")),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let (mut rt_session, _) =
+            RealtimeSession::new("key".to_owned(), Some(server.url())).unwrap();
+
+        let res = rt_session
+            .run(
+                SessionConfig::default(),
+                std::io::Cursor::new(vec![0u8; 10]),
+            )
+            .await;
+        assert!(matches!(res, Err(RealtimeError::Server(_))));
+    }
+}
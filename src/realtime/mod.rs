@@ -1,24 +1,21 @@
 //! This module is the main entrypoint for all realtime-related code, including the creation of session structs
-use anyhow::Result;
-use base64::{engine::general_purpose, Engine as _};
 use futures::{
-    pin_mut,
-    stream::{SplitSink, SplitStream},
+    future, pin_mut,
+    stream::{self, BoxStream},
     SinkExt, StreamExt,
 };
-use http::Request;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use serde_json::from_slice;
 use std::boxed::Box;
+use std::sync::{Arc, Mutex};
 use tokio::{
     io::AsyncReadExt,
-    join,
-    net::TcpStream,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    select,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
+    time,
 };
-use tokio_tungstenite::MaybeTlsStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, WebSocketStream};
 use url::Url;
 
 #[cfg(test)]
@@ -31,6 +28,64 @@ use log::{debug, error, info, warn};
 #[allow(missing_docs)]
 pub mod models;
 
+pub mod error;
+use error::Result;
+pub use error::{RealtimeError, ServerError, TimeoutKind};
+
+pub mod options;
+pub use options::RealtimeOptions;
+
+pub mod control;
+pub use control::SessionControl;
+use control::{Command, CommandSlot, PendingUpdates};
+
+pub mod resume;
+pub use resume::ReconnectPolicy;
+use resume::ReplayBuffer;
+
+pub mod retry;
+pub use retry::RetryPolicy;
+
+mod connector;
+use crate::audio::VadTimeline;
+use crate::connection::ConnectionOptions;
+
+pub mod transport;
+pub use transport::{
+    loopback, Frame, FrameSink, FrameStream, LoopbackConnection, LoopbackServer, LoopbackTransport,
+    Transport, WebsocketTransport,
+};
+
+pub mod flow;
+use flow::InFlight;
+pub use flow::{FlowControl, Lag};
+
+pub mod stats;
+use stats::StatsCollector;
+pub use stats::{LatencyHistogram, SessionStats};
+
+mod pacing;
+use pacing::Pacer;
+
+mod chunking;
+use chunking::Chunker;
+
+pub mod sink;
+pub use sink::{AudioSink, AudioSinkReader};
+
+pub mod buffer;
+pub use buffer::{MessageBuffer, OverflowPolicy};
+use buffer::{MessageQueue, MessageSender};
+
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+pub mod recording;
+pub use recording::{ReplaySession, SessionRecorder};
+
+pub mod live;
+pub use live::{LiveTranscript, TranscriptEvent};
+
 /// The default URL for the realtime runtime
 ///
 /// This is the standard URL for self-service customers, and some enterprise customers.
@@ -43,8 +98,10 @@ pub const DEFAULT_LANGUAGE: &str = "en";
 // const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Enum of all messages that can be read by an end user. This enum is passed to the receive channel that can be used to read messages
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+///
+/// Messages are decoded based on their message field. Messages which are not recognised, or which do not match the
+/// expected shape of their type, are decoded as Unknown rather than failing, so new protocol messages do not end the session.
+#[derive(Debug, Clone)]
 pub enum ReadMessage {
     /// The RecognitionStarted enum variant
     RecognitionStarted(models::RecognitionStarted),
@@ -66,6 +123,95 @@ pub enum ReadMessage {
     AudioAdded(models::AudioAdded),
     /// The EndOfTranscript enum variant
     EndOfTranscript(models::EndOfTranscript),
+    /// The EndOfUtterance enum variant, sent when end of utterance detection is enabled in the conversation_config
+    EndOfUtterance(models::EndOfUtterance),
+    /// The AudioEventStarted enum variant, sent when audio_events_config is set in the SessionConfig
+    AudioEventStarted(models::AudioEventStarted),
+    /// The AudioEventEnded enum variant, sent when audio_events_config is set in the SessionConfig
+    AudioEventEnded(models::AudioEventEnded),
+    /// Any message whose type this version of the crate does not recognise
+    Unknown {
+        /// The value of the message field, or an empty string if there was none
+        message: String,
+        /// The message as it was received
+        raw: serde_json::Value,
+    },
+}
+
+impl ReadMessage {
+    /// Decodes a message based on its message field, falling back to Unknown for messages this crate does not know.
+    /// A known message which does not decode is an error, so e.g. a malformed Error or EndOfTranscript is not missed
+    fn from_value(raw: serde_json::Value) -> serde_json::Result<Self> {
+        let message = raw
+            .get("message")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_owned();
+        match message.as_str() {
+            "RecognitionStarted" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::RecognitionStarted)
+            }
+            "Info" => serde_json::from_value(raw.clone()).map(ReadMessage::Info),
+            "Warning" => serde_json::from_value(raw.clone()).map(ReadMessage::Warning),
+            "Error" => serde_json::from_value(raw.clone()).map(ReadMessage::Error),
+            "AddPartialTranscript" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::AddPartialTranscript)
+            }
+            "AddTranscript" => serde_json::from_value(raw.clone()).map(ReadMessage::AddTranscript),
+            "AddPartialTranslation" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::AddPartialTranslation)
+            }
+            "AddTranslation" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::AddTranslation)
+            }
+            "AudioAdded" => serde_json::from_value(raw.clone()).map(ReadMessage::AudioAdded),
+            "EndOfTranscript" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::EndOfTranscript)
+            }
+            "EndOfUtterance" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::EndOfUtterance)
+            }
+            "AudioEventStarted" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::AudioEventStarted)
+            }
+            "AudioEventEnded" => {
+                serde_json::from_value(raw.clone()).map(ReadMessage::AudioEventEnded)
+            }
+            _ => Ok(ReadMessage::Unknown { message, raw }),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ReadMessage {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = <serde_json::Value as serde::Deserialize>::deserialize(deserializer)?;
+        ReadMessage::from_value(raw).map_err(serde::de::Error::custom)
+    }
+}
+
+impl serde::Serialize for ReadMessage {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ReadMessage::RecognitionStarted(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::Info(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::Warning(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::Error(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::AddPartialTranscript(mess) => {
+                serde::Serialize::serialize(mess, serializer)
+            }
+            ReadMessage::AddTranscript(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::AddPartialTranslation(mess) => {
+                serde::Serialize::serialize(mess, serializer)
+            }
+            ReadMessage::AddTranslation(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::AudioAdded(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::EndOfTranscript(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::EndOfUtterance(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::AudioEventStarted(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::AudioEventEnded(mess) => serde::Serialize::serialize(mess, serializer),
+            ReadMessage::Unknown { raw, .. } => serde::Serialize::serialize(raw, serializer),
+        }
+    }
 }
 
 /// Struct which is passed into start (and then start_recognition) to configure the realtime session.
@@ -78,6 +224,17 @@ pub struct SessionConfig {
     pub translation_config: Option<models::TranslationConfig>,
     /// Config to tell the server what kind of audio to expect. This is an optional property and defaults to raw pcm audio.
     pub audio_format: Option<models::AudioFormat>,
+    /// Sends audio at a multiple of real time, e.g. 1.0 for wall-clock speed or 2.0 for twice as fast, rather than as fast as it can be read.
+    /// This requires raw audio with the encoding and sample_rate set. This is an optional property and defaults to None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pacing: Option<f32>,
+    /// Config to enable detection of audio events such as music or applause. This is an optional property and defaults to None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio_events_config: Option<models::AudioEventsConfig>,
+    /// Sends audio in chunks of this many milliseconds, rather than as much as each read of up to 8192 bytes returns.
+    /// This requires raw audio with the encoding and sample_rate set. This is an optional property and defaults to None.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_duration_ms: Option<u32>,
 }
 
 impl SessionConfig {
@@ -100,6 +257,9 @@ impl SessionConfig {
             transcription_config: transc_conf,
             translation_config,
             audio_format,
+            pacing: None,
+            audio_events_config: None,
+            chunk_duration_ms: None,
         }
     }
 }
@@ -114,19 +274,59 @@ impl Default for SessionConfig {
             transcription_config,
             translation_config: Some(translation_config),
             audio_format: Some(audio_format),
+            pacing: None,
+            audio_events_config: None,
+            chunk_duration_ms: None,
         }
     }
 }
 
-type SplitStreamAlias = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+impl models::AudioFormat {
+    /// Returns the number of bytes in one second of single channel audio of this format.
+    ///
+    /// This is only known for raw audio with both the encoding and the sample rate set, otherwise None is returned.
+    pub fn bytes_per_second(&self) -> Option<u32> {
+        let sample_rate = u32::try_from(self.sample_rate?).ok()?;
+        Some(sample_rate * self.bytes_per_sample()?)
+    }
+
+    /// Returns the number of bytes in one sample of this format, or None if it is not raw audio with the encoding set.
+    pub fn bytes_per_sample(&self) -> Option<u32> {
+        if self.type_value != models::audio_format::Type::Raw {
+            return None;
+        }
+        Some(match self.encoding? {
+            models::audio_format::Encoding::PcmF32le => 4,
+            models::audio_format::Encoding::PcmS16le => 2,
+            models::audio_format::Encoding::Mulaw => 1,
+        })
+    }
+}
 
 /// Struct that contains everything about the session. It includes the two mains functions:
 /// - new to instantiate the session.
 /// - start to start running the session. Start is an async function that can be joined or selected with other futures
-pub struct RealtimeSession {
+///
+/// The session connects to the server through a Transport, which is a tokio-tungstenite websocket unless
+/// the session is created with with_transport.
+pub struct RealtimeSession<T: Transport = WebsocketTransport> {
     auth_token: String,
     rt_url: String,
     internal_message_sender: UnboundedSender<ReadMessage>,
+    command_sender: UnboundedSender<Command>,
+    command_receiver: UnboundedReceiver<Command>,
+    /// Holds a command_sender while run is in progress, so SessionControl handles can tell when it is not
+    commands: CommandSlot,
+    reconnect_policy: Option<ReconnectPolicy>,
+    flow_control: FlowControl,
+    options: RealtimeOptions,
+    retry_policy: RetryPolicy,
+    transport: T,
+    recorder: Option<Arc<SessionRecorder>>,
+    vad_timeline: Option<VadTimeline>,
+    lag_sender: Arc<watch::Sender<Lag>>,
+    stats: Arc<Mutex<StatsCollector>>,
+    abort_sender: Arc<watch::Sender<bool>>,
 }
 
 impl RealtimeSession {
@@ -146,8 +346,36 @@ impl RealtimeSession {
     pub fn new(
         auth_token: String,
         rt_url: Option<String>,
+    ) -> Result<(Self, UnboundedReceiver<ReadMessage>)> {
+        Self::with_transport(auth_token, rt_url, WebsocketTransport::default())
+    }
+
+    /// Sets how the session connects to the server, e.g. through a proxy, with a private CA or with rustls.
+    /// See ConnectionOptions for the defaults.
+    pub fn set_connection_options(&mut self, options: ConnectionOptions) {
+        self.transport = WebsocketTransport::new(options);
+    }
+}
+
+impl<T: Transport> RealtimeSession<T> {
+    /// Instantiates a RealtimeSession which connects to the server through the given Transport rather than a websocket,
+    /// e.g. a LoopbackTransport in tests or a wrapper around another websocket library.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (transport, server) = loopback();
+    /// let (rt_session, _) = RealtimeSession::with_transport("YOUR_API_KEY".to_owned(), None, transport).unwrap();
+    /// ```
+    pub fn with_transport(
+        auth_token: String,
+        rt_url: Option<String>,
+        transport: T,
     ) -> Result<(Self, UnboundedReceiver<ReadMessage>)> {
         let (channel_sender, channel_receiver) = unbounded_channel::<ReadMessage>();
+        let (command_sender, command_receiver) = unbounded_channel::<Command>();
+        let (lag_sender, _) = watch::channel(Lag::default());
+        let (abort_sender, _) = watch::channel(false);
         let mut url = DEFAULT_RT_URL.to_owned();
         if let Some(temp_url) = rt_url {
             url = temp_url
@@ -157,126 +385,188 @@ impl RealtimeSession {
             auth_token,
             rt_url: url,
             internal_message_sender: channel_sender,
+            command_sender,
+            command_receiver,
+            commands: Arc::default(),
+            reconnect_policy: None,
+            flow_control: FlowControl::default(),
+            options: RealtimeOptions::default(),
+            retry_policy: RetryPolicy::default(),
+            transport,
+            recorder: None,
+            vad_timeline: None,
+            lag_sender: Arc::new(lag_sender),
+            stats: Arc::default(),
+            abort_sender: Arc::new(abort_sender),
         };
         Ok((sesh, channel_receiver))
     }
 
-    /// connect is an internal function that handles the TCP handshake, TLS handshake and websocket handshake
-    /// It ultimately returns the send and receive parts of the websocket.
-    async fn connect(&mut self) -> Result<(SenderWrapper, SplitStreamAlias)> {
-        let sec_key: String = thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect();
-        let b64 = general_purpose::STANDARD.encode(sec_key);
+    /// Returns a SessionControl handle, which can be moved into another task to interact with the session whilst run is in progress.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (rt_session, _) = RealtimeSession::new("YOUR_API_KEY".to_owned(), None).unwrap();
+    /// let control = rt_session.control();
+    ///
+    /// tokio::spawn(async move {
+    ///     let mut config = models::TranscriptionConfig::new("en".to_owned());
+    ///     config.max_delay = Some(2.0);
+    ///     control.set_recognition_config(config).await.unwrap();
+    /// });
+    /// ```
+    pub fn control(&self) -> SessionControl {
+        SessionControl::new(
+            self.commands.clone(),
+            self.lag_sender.subscribe(),
+            self.stats.clone(),
+            self.abort_sender.clone(),
+        )
+    }
+
+    /// Returns the latency and throughput metrics of the current or last run of the session.
+    /// They can also be read through SessionControl::stats while run is in progress.
+    pub fn stats(&self) -> SessionStats {
+        self.stats.lock().unwrap().snapshot()
+    }
+
+    /// Creates a push-style audio input for the session, as an alternative to reading audio from a file or other AsyncRead source.
+    ///
+    /// The AudioSinkReader is passed to run in place of a reader, whilst the AudioSink can be cloned and moved into other tasks
+    /// to push frames of audio, e.g. from a websocket or an RTP stream. Calling AudioSink::finish ends the session cleanly.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (mut rt_session, _) = RealtimeSession::new("YOUR_API_KEY".to_owned(), None).unwrap();
+    /// let (audio_sink, audio_reader) = rt_session.audio_sink();
+    ///
+    /// tokio::spawn(async move {
+    ///     audio_sink.push(vec![0u8; 3200]).unwrap();
+    ///     audio_sink.finish().unwrap();
+    /// });
+    ///
+    /// rt_session.run(SessionConfig::default(), audio_reader).await.unwrap();
+    /// ```
+    pub fn audio_sink(&self) -> (AudioSink, AudioSinkReader) {
+        sink::audio_sink()
+    }
+
+    /// Enables or disables the resilient mode of the session. It is disabled by default.
+    ///
+    /// In resilient mode, if the connection drops whilst run is in progress, the session reconnects
+    /// and replays any audio the server had not yet acknowledged, rather than returning an error.
+    /// See ReconnectPolicy for the details and limitations.
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Sets how much audio may be sent to the server before it has been acknowledged. By default there is no limit.
+    ///
+    /// The current lag of the server can be read through SessionControl::lag.
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) {
+        self.flow_control = flow_control;
+    }
+
+    /// Sets how sending a message to the server is retried if it fails for a transient reason. See RetryPolicy for the defaults.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Sets the timeouts and keepalive of the session. See RealtimeOptions for the defaults.
+    pub fn set_options(&mut self, options: RealtimeOptions) {
+        self.options = options;
+    }
+
+    /// Records every run of the session into an archive, which can be played back with ReplaySession. It is disabled by default.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (mut rt_session, _) = RealtimeSession::new("YOUR_API_KEY".to_owned(), None).unwrap();
+    /// rt_session.set_recorder(Some(SessionRecorder::create("session.jsonl").unwrap()));
+    /// ```
+    pub fn set_recorder(&mut self, recorder: Option<SessionRecorder>) {
+        self.recorder = recorder.map(Arc::new);
+    }
+
+    /// Maps the times in results back onto the original audio, when the audio passed to run is gated by a
+    /// VoiceActivityDetector. It is disabled by default, which leaves times relative to the audio sent.
+    /// SessionStats are always measured against the audio sent.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let vad = VoiceActivityDetector::new(PcmFormat::new(SampleFormat::S16, 16000, 1), VadConfig::default()).unwrap();
+    /// rt_session.set_vad_timeline(Some(vad.timeline()));
+    /// rt_session.run(config, PipelineReader::new(audio_reader, vad)).await.unwrap();
+    /// ```
+    pub fn set_vad_timeline(&mut self, timeline: Option<VadTimeline>) {
+        self.vad_timeline = timeline;
+    }
 
+    /// connect is an internal function that opens a connection through the Transport, within the connect_timeout.
+    /// It ultimately returns the send and receive parts of the connection.
+    async fn connect(&mut self) -> Result<(FrameSink, FrameStream)> {
         let uri = Url::parse(&self.rt_url)?;
-        let authority = uri.authority();
-        let host = authority
-            .find('@')
-            .map(|idx| authority.split_at(idx + 1).1)
-            .unwrap_or_else(|| authority);
-
-        if host.is_empty() {
-            return Err(anyhow::Error::from(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "uri host was empty",
-            )));
-        }
-        let auth_header = format!("Bearer {}", self.auth_token.clone());
-
-        let req = Request::builder()
-            .method("GET")
-            .header("Host", host)
-            .header("Connection", "keep-alive, Upgrade")
-            .header("Upgrade", "websocket")
-            .header("Sec-WebSocket-Version", "13")
-            .header("Sec-WebSocket-Key", b64)
-            .header("Authorization", auth_header)
-            .uri(&self.rt_url)
-            .body(())?;
-
-        let (stream, res) = connect_async(req).await?;
-        if let Some(resp) = res.body() {
-            error!("failed to connect {:?}", resp);
+        let connecting = self.transport.connect(&uri, &self.auth_token);
+        match self.options.connect_timeout {
+            Some(timeout) => time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| RealtimeError::Timeout(TimeoutKind::Connect))?,
+            None => connecting.await,
         }
-
-        let (writer, reader) = stream.split();
-        let sender = SenderWrapper::new(writer);
-        Ok((sender, reader))
     }
 
     /// Wait for start reads messages in a loop until one of a set of coniditions is met:
     /// 1. We receive RecognitionStarted, at which point the rt session begins in earnest
-    /// 2. We receive an error, in which case we exit
-    /// 3. We receive some other message, in which case we retry the function a set number of times
-    async fn wait_for_start(
-        &mut self,
-        receiver: &mut SplitStreamAlias,
-        channel_sender: &tokio::sync::mpsc::UnboundedSender<ReadMessage>,
-    ) -> Result<()> {
-        let mut retries = 0;
-        let max_retries = 5;
-        let mut success = false;
-        while !success {
-            let value = receiver.next().await;
-            if let Some(val) = value {
-                let message = match val {
-                    Ok(v) => v,
-                    Err(err) => {
-                        warn!("Failed to get data from stream, {:?}", err);
-                        retries += 1;
-                        if retries > max_retries {
-                            return Err(Into::into(std::io::Error::new(
-                                std::io::ErrorKind::ConnectionAborted,
-                                "Recognition failed to start on the server",
-                            )));
-                        }
-                        continue;
-                    }
-                };
-                
-                let bin_data = message.into_data();
-                // this deserialise will fail if not the right message type
-                match serde_json::from_slice::<models::RecognitionStarted>(&bin_data) {
-                    Ok(mess) => {
-                        success = true;
-                        channel_sender.send(ReadMessage::RecognitionStarted(mess))?;
-                    }
-                    Err(err) => {
-                        warn!(
-                            "Could not read value of message into RecognitionStarted struct, {:?}",
-                            err
-                        );
-                        match serde_json::from_slice::<models::Error>(&bin_data) {
-                            Ok(mess) => {
-                                return Err(Into::into(std::io::Error::new(
-                                    std::io::ErrorKind::ConnectionAborted,
-                                    format!("Received error from server {}", mess.reason),
-                                )));
-                            }
-                            Err(_) => {
-                                retries += 1;
-                                if retries > max_retries {
-                                    return Err(Into::into(std::io::Error::new(
-                                        std::io::ErrorKind::ConnectionAborted,
-                                        "Recognition failed to start on the server",
-                                    )));
-                                }
-                                continue;
-                            }
-                        }
-                    }
-                };
-            } else {
-                return Err(Into::into(std::io::Error::new(
-                    std::io::ErrorKind::TimedOut,
-                    "Failed to receive message from the server",
-                )));
+    /// 2. We receive an error or the connection fails, in which case we exit
+    /// 3. The start_timeout passes, in which case we exit
+    ///
+    /// Any other messages are logged and skipped.
+    async fn wait_for_start(&mut self, receiver: &mut FrameStream, state: &RunState) -> Result<()> {
+        let deadline = self
+            .options
+            .start_timeout
+            .and_then(|timeout| time::Instant::now().checked_add(timeout));
+        loop {
+            let next = receiver.next();
+            let value = match deadline {
+                Some(deadline) => time::timeout_at(deadline, next)
+                    .await
+                    .map_err(|_| RealtimeError::Timeout(TimeoutKind::RecognitionStarted))?,
+                None => next.await,
+            };
+            let message = match value {
+                Some(message) => message?,
+                None => {
+                    warn!("The server closed the connection before recognition started");
+                    return Err(RealtimeError::connection_closed());
+                }
+            };
+            if !is_data_frame(&message) {
+                continue;
             }
+
+            let bin_data = message.into_data();
+            // this deserialise will fail if not the right message type
+            match serde_json::from_slice::<models::RecognitionStarted>(&bin_data) {
+                Ok(mess) => {
+                    state.deliver(ReadMessage::RecognitionStarted(mess)).await?;
+                    return Ok(());
+                }
+                Err(err) => {
+                    warn!(
+                        "Could not read value of message into RecognitionStarted struct, {:?}",
+                        err
+                    );
+                    if let Ok(mess) = serde_json::from_slice::<models::Error>(&bin_data) {
+                        return Err(RealtimeError::Server(ServerError::from(&mess)));
+                    }
+                }
+            };
         }
-        Ok(())
     }
 
     /// The main function of the RealtimeSession struct. It connects to the WebSocket,
@@ -284,10 +574,11 @@ impl RealtimeSession {
     /// the start of the session and then concurrently sends audio data and calls the user-registered handler functions.
     ///
     /// The config parameter sets the SessionConfig for the transcriber, including transcription, translation and audio source config.
-    /// Although it is not yet, implemented, it is possible to update transcriber config on the fly.
+    /// The transcription config can be updated on the fly through the handle returned by the control method.
     ///
     /// The reader parameter accepts anything that satisfies Read and Send e.g. a File, a BufReader, a Cursor.
     /// This allows the user to flexibly provide any audio source of their choice.
+    /// Sources which produce frames of audio can use the reader half of audio_sink instead.
     ///
     /// # Example
     ///
@@ -307,73 +598,376 @@ impl RealtimeSession {
     ///
     /// # Errors
     ///
-    /// This function can fail in a number of ways:
+    /// This function can fail in a number of ways, each with its own RealtimeError variant:
     ///     - If the audio  read loop fails, the connection will be closed and the audio failure will be returned
-    ///     - If the server sends an error message, this will be returned as an error and the audio read loop will stop
-    ///     - If something goes wrong deserialising json or handling the local websocket, the error will be returned
+    ///     - If the server sends an error message, this will be returned as RealtimeError::Server and the audio read loop will stop
+    ///     - If the connection drops or the handshake fails, this will be returned as RealtimeError::Transport, Tls or Handshake
+    ///     - If something goes wrong deserialising json, this will be returned as RealtimeError::Serialization
+    ///     - If a stage of the session takes longer than the RealtimeOptions allow, this will be returned as RealtimeError::Timeout
+    ///
+    /// RealtimeError::is_retryable tells whether it is worth running the session again.
     pub async fn run<R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static>(
         &mut self,
         config: SessionConfig,
         reader: R,
-    ) -> Result<(), anyhow::Error> {
-        let (mut sock_sender, mut sock_receiver) = self.connect().await?;
-        sock_sender.start_recognition(config).await?;
-        self.wait_for_start(&mut sock_receiver, &self.internal_message_sender.clone())
-            .await?;
-
-        let sender = &self.internal_message_sender.clone();
-        let process_messages = { RealtimeSession::process_messages(&mut sock_receiver, sender) };
-        let send_audio = { sock_sender.send_audio(reader) };
-
-        pin_mut!(process_messages, send_audio);
-        let (messages_res, audio_res) = join!(process_messages, send_audio);
-        match audio_res {
-            Ok(_) => debug!("No issues in audio processing task"),
-            Err(err) => return Err(err),
+    ) -> Result<()> {
+        let output = MessageSender::Unbounded(self.internal_message_sender.clone());
+        self.run_with_output(config, reader, output).await
+    }
+
+    /// Like run, but returns the messages from the server as a Stream rather than through the channel returned by new.
+    ///
+    /// Messages are held in a bounded buffer until they are consumed, and the buffer config decides what happens when it is full.
+    /// Final transcripts and translations are never dropped. The stream ends once the session has finished,
+    /// and if the session fails, the error is the last item of the stream.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (mut rt_session, _) = RealtimeSession::new("YOUR_API_KEY".to_owned(), None).unwrap();
+    /// let file = File::open("SOME_FILE_PATH").await.unwrap();
+    ///
+    /// let buffer = MessageBuffer {
+    ///     capacity: 64,
+    ///     overflow: OverflowPolicy::DropPartials,
+    /// };
+    /// let mut messages = rt_session.run_stream(SessionConfig::default(), file, buffer);
+    /// while let Some(message) = messages.next().await {
+    ///     println!("{:?}", message.unwrap());
+    /// }
+    /// ```
+    pub fn run_stream<'a, R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static>(
+        &'a mut self,
+        config: SessionConfig,
+        reader: R,
+        buffer: MessageBuffer,
+    ) -> BoxStream<'a, Result<ReadMessage>> {
+        let queue = Arc::new(MessageQueue::new(buffer));
+        let output = MessageSender::Bounded(queue.clone());
+        let run_queue = queue.clone();
+        let run = async move {
+            let res = self.run_with_output(config, reader, output).await;
+            run_queue.close(res.err());
+            None
         };
-        match messages_res {
-            Ok(_) => debug!("No issues detected whilst processing server-sent messages"),
-            Err(err) => {
-                error!("{:?}", err);
+        let messages = stream::unfold(queue, |queue| async move {
+            queue.pop().await.map(|item| (item, queue))
+        });
+        stream::select(messages, stream::once(run).filter_map(future::ready)).boxed()
+    }
+
+    async fn run_with_output<R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static>(
+        &mut self,
+        mut config: SessionConfig,
+        mut reader: R,
+        output: MessageSender,
+    ) -> Result<()> {
+        let bytes_per_second = config
+            .audio_format
+            .as_ref()
+            .and_then(|format| format.bytes_per_second());
+        if self.reconnect_policy.is_some() && bytes_per_second.is_none() {
+            return Err(RealtimeError::Config(
+                "Resilient mode requires raw audio with the encoding and sample_rate set"
+                    .to_owned(),
+            ));
+        }
+        if let Some(speed) = config.pacing {
+            if bytes_per_second.is_none() || !pacing::is_valid_speed(speed) {
+                return Err(RealtimeError::Config(
+                    "Pacing requires a positive speed and raw audio with the encoding and sample_rate set"
+                        .to_owned(),
+                ));
+            }
+        }
+        let chunker = Chunker::new(config.audio_format.as_ref(), config.chunk_duration_ms)
+            .ok_or_else(|| {
+                RealtimeError::Config(
+                    "A chunk duration requires raw audio with the encoding and sample_rate set, and room for a whole sample"
+                        .to_owned(),
+                )
+            })?;
+
+        let state = RunState {
+            output,
+            pending_updates: Mutex::new(PendingUpdates::default()),
+            replay_buffer: Mutex::new(ReplayBuffer::new(self.reconnect_policy.as_ref())),
+            bytes_per_second,
+            stop_deadline: Mutex::new(None),
+            stop_waiters: Mutex::new(vec![]),
+            recorder: self.recorder.clone(),
+            vad_timeline: self.vad_timeline.clone(),
+            stats: self.stats.clone(),
+        };
+        state.stats.lock().unwrap().reset(bytes_per_second);
+        if let Some(recorder) = &state.recorder {
+            recorder.start(&config);
+        }
+        // an abort from before this run started does not apply to it
+        self.abort_sender.send_replace(false);
+        let mut aborted = self.abort_sender.subscribe();
+        self.reject_commands();
+        *self.commands.lock().unwrap() = Some(self.command_sender.clone());
+        let max_duration = self.options.max_duration;
+        let res = select! {
+            res = self.run_with_reconnects(&mut config, &mut reader, chunker, &state) => res,
+            _ = time::sleep(max_duration.unwrap_or_default()), if max_duration.is_some() => {
+                warn!("The session reached its maximum duration of {:?}", max_duration);
+                Err(RealtimeError::Timeout(TimeoutKind::MaxDuration))
+            }
+            _ = aborted.wait_for(|aborted| *aborted) => {
+                info!("The session was aborted");
+                Err(RealtimeError::Aborted)
+            }
+        };
+        self.abort_sender.send_replace(false);
+        self.commands.lock().unwrap().take();
+        self.reject_commands();
+        state.stats.lock().unwrap().finish();
+        for stop_waiter in state.stop_waiters.lock().unwrap().drain(..) {
+            let stop_res = match &res {
+                Ok(()) => Ok(()),
+                Err(RealtimeError::Timeout(kind)) => Err(RealtimeError::Timeout(*kind)),
+                Err(err) => Err(RealtimeError::Closed(format!(
+                    "The session did not stop cleanly: {}",
+                    err
+                ))),
+            };
+            let _ = stop_waiter.send(stop_res);
+        }
+        res
+    }
+
+    /// Fails the commands left over from a run, which would otherwise wait forever or be handled by the next run
+    fn reject_commands(&mut self) {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            command.reject();
+        }
+    }
+
+    /// Runs the session, reconnecting as set out by the ReconnectPolicy if the connection drops
+    async fn run_with_reconnects<
+        R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static,
+    >(
+        &mut self,
+        config: &mut SessionConfig,
+        reader: &mut R,
+        mut chunker: Chunker,
+        state: &RunState,
+    ) -> Result<()> {
+        let mut resuming = false;
+        let mut attempt = 0;
+        loop {
+            let mut connected = false;
+            let res = self
+                .run_connection(
+                    config,
+                    reader,
+                    &mut chunker,
+                    state,
+                    resuming,
+                    &mut connected,
+                )
+                .await;
+            let err = match res {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let policy = match &self.reconnect_policy {
+                Some(policy) if err.is_disconnect() => policy,
+                _ => {
+                    error!("{:?}", err);
+                    return Err(err);
+                }
+            };
+            if connected {
+                attempt = 0;
+            }
+            attempt += 1;
+            if attempt > policy.max_attempts {
+                error!(
+                    "Giving up on reconnecting after {} attempts",
+                    policy.max_attempts
+                );
                 return Err(err);
             }
+            let backoff = policy.backoff(attempt);
+            warn!(
+                "Connection lost ({:?}), reconnecting in {:?} (attempt {})",
+                err, backoff, attempt
+            );
+            tokio::time::sleep(backoff).await;
+            resuming = true;
+        }
+    }
+
+    /// Runs the session over a single websocket connection, resuming the session first if a previous connection dropped.
+    async fn run_connection<R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static>(
+        &mut self,
+        config: &mut SessionConfig,
+        reader: &mut R,
+        chunker: &mut Chunker,
+        state: &RunState,
+        resuming: bool,
+        connected: &mut bool,
+    ) -> Result<()> {
+        let bytes_per_second = state.bytes_per_second;
+        let (writer, mut sock_receiver) = self.connect().await?;
+        let in_flight = InFlight::new(
+            self.flow_control.clone(),
+            bytes_per_second,
+            self.lag_sender.clone(),
+        );
+        let pacer = config
+            .pacing
+            .zip(bytes_per_second)
+            .and_then(|(speed, bytes_per_second)| Pacer::new(speed, bytes_per_second));
+        let mut sock_sender = SenderWrapper::new(
+            writer,
+            in_flight,
+            pacer,
+            self.options.ping_interval,
+            self.retry_policy.clone(),
+        );
+        sock_sender.start_recognition(config.clone()).await?;
+        self.wait_for_start(&mut sock_receiver, state).await?;
+        *connected = true;
+
+        let mut time_offset = 0.0;
+        if resuming {
+            let replay = state.replay_buffer.lock().unwrap().resume();
+            time_offset = state
+                .replay_buffer
+                .lock()
+                .unwrap()
+                .time_offset(bytes_per_second.unwrap_or_default());
+            // StartRecognition was sent with the latest config, so earlier updates are now in effect
+            state.pending_updates.lock().unwrap().acknowledge_all();
+            info!(
+                "Resumed session at {}s, replaying {} unacknowledged audio chunks",
+                time_offset,
+                replay.len()
+            );
+            for (seq_no, chunk) in replay.iter() {
+                if let Some(recorder) = &state.recorder {
+                    recorder.audio(*seq_no, chunk, true);
+                }
+                sock_sender.send_chunk(chunk).await?;
+            }
+        }
+
+        let (ack_sender, mut ack_receiver) = watch::channel(0);
+        let messages_res = {
+            let process_messages = {
+                Self::process_messages(
+                    &mut sock_receiver,
+                    state,
+                    &ack_sender,
+                    time_offset,
+                    self.options.idle_timeout,
+                )
+            };
+            let send_audio = {
+                sock_sender.send_audio(
+                    reader,
+                    chunker,
+                    &mut self.command_receiver,
+                    &mut ack_receiver,
+                    &mut config.transcription_config,
+                    state,
+                )
+            };
+
+            pin_mut!(process_messages, send_audio);
+            let mut audio_done = false;
+            loop {
+                select! {
+                    messages_res = &mut process_messages => break messages_res,
+                    audio_res = &mut send_audio, if !audio_done => {
+                        audio_done = true;
+                        match audio_res {
+                            Ok(_) => debug!("No issues in audio processing task"),
+                            Err(err) => return Err(err),
+                        };
+                    }
+                }
+            }
         };
+        match messages_res {
+            Ok(_) => debug!("No issues detected whilst processing server-sent messages"),
+            Err(err) => return Err(err),
+        };
+        // the session is over, so a failure to close the websocket cleanly is not worth reporting
+        let _ = sock_sender.socket.close().await;
         Ok(())
     }
 
     async fn process_messages(
-        receiver: &mut SplitStreamAlias,
-        channel_sender: &tokio::sync::mpsc::UnboundedSender<ReadMessage>,
+        receiver: &mut FrameStream,
+        state: &RunState,
+        ack_sender: &watch::Sender<i32>,
+        time_offset: f32,
+        idle_timeout: Option<std::time::Duration>,
     ) -> Result<()> {
         let mut running = true;
         while running {
-            let result = receiver.next().await;
+            let result = match idle_timeout {
+                Some(timeout) => time::timeout(timeout, receiver.next())
+                    .await
+                    .map_err(|_| RealtimeError::Timeout(TimeoutKind::Idle))?,
+                None => receiver.next().await,
+            };
             if let Some(val) = result {
                 let mess = val?;
+                if !is_data_frame(&mess) {
+                    continue;
+                }
                 let data = mess.into_data();
                 // Parse the string of data into serde_json::Value.
-                let value = from_slice::<ReadMessage>(&data)?;
+                let mut value = from_slice::<ReadMessage>(&data)?;
+                resume::shift_timestamps(&mut value, time_offset);
+                // latencies are measured against the audio actually sent, so before any silence gated out is added back
+                state.stats.lock().unwrap().received(&value);
+                if let Some(timeline) = &state.vad_timeline {
+                    resume::map_timestamps(&mut value, |time| timeline.to_original(time));
+                }
                 match value {
                     ReadMessage::EndOfTranscript(mess) => {
                         debug!("detected EndOfTranscript message, quitting");
                         running = false;
-                        channel_sender.send(ReadMessage::EndOfTranscript(mess))?;
+                        state.pending_updates.lock().unwrap().acknowledge_all();
+                        state.deliver(ReadMessage::EndOfTranscript(mess)).await?;
                     }
                     ReadMessage::Error(mess) => {
-                        channel_sender.send(ReadMessage::Error(mess.clone()))?;
-                        error!("Received error from server {}", mess.reason);
-                        return Err(Into::into(std::io::Error::new(
-                            std::io::ErrorKind::ConnectionAborted,
-                            format!("Received error from server {}", mess.reason),
-                        )));
+                        let server_error = ServerError::from(&mess);
+                        state
+                            .pending_updates
+                            .lock()
+                            .unwrap()
+                            .fail_all(&server_error);
+                        state.deliver(ReadMessage::Error(mess)).await?;
+                        error!("Received error from server {}", server_error);
+                        return Err(RealtimeError::Server(server_error));
+                    }
+                    ReadMessage::AudioAdded(mut mess) => {
+                        state
+                            .pending_updates
+                            .lock()
+                            .unwrap()
+                            .acknowledge(mess.seq_no);
+                        ack_sender.send_replace(mess.seq_no);
+                        mess.seq_no = state.replay_buffer.lock().unwrap().acknowledge(mess.seq_no);
+                        state.deliver(ReadMessage::AudioAdded(mess)).await?;
                     }
-                    mess => channel_sender.send(mess)?,
+                    ReadMessage::Unknown { message, raw } => {
+                        warn!("Received unrecognised message {:?}, passing it on", message);
+                        state.deliver(ReadMessage::Unknown { message, raw }).await?;
+                    }
+                    mess => state.deliver(mess).await?,
                 }
             } else {
-                return Err(Into::into(std::io::Error::new(
-                    std::io::ErrorKind::ConnectionAborted,
-                    "Did not receive a message".to_string(),
-                )));
+                warn!("The server closed the connection before EndOfTranscript");
+                return Err(RealtimeError::connection_closed());
             }
         }
         debug!("Exited message processing loop");
@@ -381,76 +975,226 @@ impl RealtimeSession {
     }
 }
 
+/// State which lasts for a whole call to run, across any reconnects
+struct RunState {
+    output: MessageSender,
+    pending_updates: Mutex<PendingUpdates>,
+    replay_buffer: Mutex<ReplayBuffer>,
+    bytes_per_second: Option<u32>,
+    /// When the session must have finished by, once SessionControl::stop has been called
+    stop_deadline: Mutex<Option<time::Instant>>,
+    stop_waiters: Mutex<Vec<oneshot::Sender<Result<()>>>>,
+    recorder: Option<Arc<SessionRecorder>>,
+    vad_timeline: Option<VadTimeline>,
+    stats: Arc<Mutex<StatsCollector>>,
+}
+
+impl RunState {
+    /// Passes a message from the server on to the consumer of the session, recording it first if the session is being recorded
+    async fn deliver(&self, message: ReadMessage) -> Result<()> {
+        if let Some(recorder) = &self.recorder {
+            recorder.message(&message);
+        }
+        self.output.send(message).await
+    }
+}
+
+/// Returns false for control frames such as pings and pongs, which carry no message from the server
+fn is_data_frame(message: &Frame) -> bool {
+    if message.is_data() {
+        return true;
+    }
+    debug!("Skipping websocket control frame {:?}", message);
+    false
+}
+
 struct SenderWrapper {
-    pub socket: SplitSink<
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        tokio_tungstenite::tungstenite::Message,
-    >,
+    pub socket: FrameSink,
     last_seq_no: i32,
+    in_flight: InFlight,
+    pacer: Option<Pacer>,
+    ping_interval: Option<std::time::Duration>,
+    retry_policy: RetryPolicy,
 }
 
 impl SenderWrapper {
     fn new(
-        socket: SplitSink<
-            WebSocketStream<MaybeTlsStream<TcpStream>>,
-            tokio_tungstenite::tungstenite::Message,
-        >,
+        socket: FrameSink,
+        in_flight: InFlight,
+        pacer: Option<Pacer>,
+        ping_interval: Option<std::time::Duration>,
+        retry_policy: RetryPolicy,
     ) -> Self {
         Self {
             socket,
             last_seq_no: 0,
+            in_flight,
+            pacer,
+            ping_interval,
+            retry_policy,
         }
     }
 
     async fn send_audio<R: AsyncReadExt + std::marker::Send + std::marker::Unpin + 'static>(
         &mut self,
-        mut reader: R,
+        reader: &mut R,
+        chunker: &mut Chunker,
+        commands: &mut UnboundedReceiver<Command>,
+        acks: &mut watch::Receiver<i32>,
+        transcription_config: &mut models::TranscriptionConfig,
+        state: &RunState,
     ) -> Result<()> {
-        let mut buffer = vec![0u8; 8192];
+        if chunker.reader_finished {
+            self.send_close(self.last_seq_no).await?;
+        }
+        // once EndOfStream has been sent, this keeps handling commands until EndOfTranscript ends the connection
+        let mut ping = self.ping_interval.and_then(|period| {
+            let start = time::Instant::now().checked_add(period)?;
+            Some(time::interval_at(start, period))
+        });
         loop {
-            debug!("reading audio data");
-            match reader.read(&mut buffer).await {
-                Ok(no) => {
-                    if no == 0 {
-                        info!("Reader was empty, closing stream");
-                        self.send_close(self.last_seq_no).await?;
-                        return Ok(());
-                    } else {
-                        debug!("Sending audio length {no}");
-                        let tu_message = Message::from(&buffer[..no]);
-                        self.send_message(tu_message).await?;
-                        self.last_seq_no += 1;
+            let reading = !chunker.reader_finished;
+            let window_full = self.in_flight.is_full();
+            let paced_until = self.pacer.as_ref().and_then(|pacer| pacer.next_send_at());
+            let stop_deadline = *state.stop_deadline.lock().unwrap();
+            if reading && window_full {
+                debug!("waiting for the server to acknowledge audio");
+            } else if reading && paced_until.is_none() {
+                debug!("reading audio data");
+            }
+            select! {
+                read_res = reader.read(chunker.space()), if reading && !window_full && paced_until.is_none() => match read_res {
+                    Ok(no) => {
+                        if no == 0 {
+                            info!("Reader was empty, closing stream");
+                            self.close_audio(chunker, state).await?;
+                        } else if let Some(chunk) = chunker.fill(no) {
+                            self.send_audio_chunk(&chunk, state).await?;
+                        }
                     }
-                }
-                Err(_) => {
-                    info!("encountered an error reading audio data, closing the stream");
-                    self.send_close(self.last_seq_no).await?;
-                }
-            };
+                    Err(_) => {
+                        info!("encountered an error reading audio data, closing the stream");
+                        self.close_audio(chunker, state).await?;
+                    }
+                },
+                _ = time::sleep_until(paced_until.unwrap_or_else(time::Instant::now)), if reading && paced_until.is_some() => {},
+                _ = time::sleep_until(stop_deadline.unwrap_or_else(time::Instant::now)), if stop_deadline.is_some() => {
+                    warn!("The server did not send EndOfTranscript before the stop deadline, closing the connection");
+                    let _ = self.socket.close().await;
+                    return Err(RealtimeError::Timeout(TimeoutKind::Stop));
+                },
+                _ = async { ping.as_mut().unwrap().tick().await }, if ping.is_some() => {
+                    debug!("Sending websocket ping");
+                    self.send_message(Frame::Ping(vec![])).await?;
+                },
+                Ok(()) = acks.changed() => {
+                    let seq_no = *acks.borrow_and_update();
+                    self.in_flight.acknowledge(seq_no);
+                },
+                Some(command) = commands.recv() => match command {
+                    Command::SetRecognitionConfig(_, ack_sender) if chunker.reader_finished => {
+                        let _ = ack_sender.send(Err(RealtimeError::Closed(
+                            "The audio stream has already ended".to_owned(),
+                        )));
+                    }
+                    Command::SetRecognitionConfig(new_config, ack_sender) => {
+                        if let Err(err) =
+                            control::validate_config_update(transcription_config, &new_config)
+                        {
+                            let _ = ack_sender.send(Err(err));
+                            continue;
+                        }
+                        self.set_recognition_config(new_config.clone()).await?;
+                        state
+                            .pending_updates
+                            .lock()
+                            .unwrap()
+                            .push(self.last_seq_no, ack_sender);
+                        *transcription_config = new_config;
+                    }
+                    Command::ForceEndOfUtterance(ack_sender) => {
+                        // if sending fails the ack sender is dropped, which the caller sees as the session ending
+                        if let Some(chunk) = chunker.flush() {
+                            self.send_audio_chunk(&chunk, state).await?;
+                        }
+                        self.force_end_of_utterance().await?;
+                        let _ = ack_sender.send(Ok(()));
+                    }
+                    Command::Stop(timeout, ack_sender) => {
+                        state.stop_waiters.lock().unwrap().push(ack_sender);
+                        // a timeout too long to represent leaves the session to finish in its own time
+                        if let Some(deadline) = time::Instant::now().checked_add(timeout) {
+                            let mut stop_deadline = state.stop_deadline.lock().unwrap();
+                            if stop_deadline.is_none_or(|current| deadline < current) {
+                                *stop_deadline = Some(deadline);
+                            }
+                        }
+                        if !chunker.reader_finished {
+                            info!("Stopping the session, closing stream");
+                            self.close_audio(chunker, state).await?;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Sends the audio left in the chunker followed by EndOfStream, once the reader has ended or the session is stopping
+    async fn close_audio(&mut self, chunker: &mut Chunker, state: &RunState) -> Result<()> {
+        if let Some(chunk) = chunker.finish() {
+            self.send_audio_chunk(&chunk, state).await?;
+        }
+        chunker.reader_finished = true;
+        self.send_close(self.last_seq_no).await
+    }
+
+    /// Sends a chunk of audio from the reader, keeping it for replay and recording it first
+    async fn send_audio_chunk(&mut self, chunk: &[u8], state: &RunState) -> Result<()> {
+        debug!("Sending audio length {}", chunk.len());
+        let seq_no = state.replay_buffer.lock().unwrap().push(chunk);
+        if let Some(recorder) = &state.recorder {
+            recorder.audio(seq_no, chunk, false);
+        }
+        state.stats.lock().unwrap().audio_sent(chunk.len());
+        self.send_chunk(chunk).await?;
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.sent(chunk.len());
         }
+        Ok(())
     }
 
-    async fn send_message(&mut self, message: Message) -> Result<()> {
+    async fn send_chunk(&mut self, chunk: &[u8]) -> Result<()> {
+        self.send_message(Frame::Binary(chunk.to_vec())).await?;
+        self.last_seq_no += 1;
+        self.in_flight.sent(self.last_seq_no, chunk.len());
+        Ok(())
+    }
+
+    async fn send_message(&mut self, message: Frame) -> Result<()> {
         let mut retries = 0;
-        let max_retries = 5;
-        let mut success = false;
-        while !success {
+        loop {
             match self.socket.send(message.clone()).await {
-                Ok(()) => (),
-                Err(err) => {
-                    println!("err: {:?}", err);
+                Ok(()) => return Ok(()),
+                Err(err)
+                    if retry::is_transient(&err) && retries < self.retry_policy.max_retries =>
+                {
                     retries += 1;
-                    if retries >= max_retries {
-                        self.socket.send(message).await?;
-                        panic!("arg too many attempts to send")
-                    }
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                    continue;
+                    let backoff = self.retry_policy.backoff(retries);
+                    warn!(
+                        "Failed to send message ({:?}), retrying in {:?} (retry {})",
+                        err, backoff, retries
+                    );
+                    time::sleep(backoff).await;
                 }
-            };
-            success = true
+                Err(err) => {
+                    error!(
+                        "Failed to send message after {} retries: {:?}",
+                        retries, err
+                    );
+                    return Err(err);
+                }
+            }
         }
-        Ok(())
     }
 
     async fn start_recognition(&mut self, config: SessionConfig) -> Result<()> {
@@ -462,9 +1206,28 @@ impl SenderWrapper {
         if let Some(transl) = config.translation_config {
             message.translation_config = Some(Box::new(transl));
         }
+        if let Some(audio_events) = config.audio_events_config {
+            message.audio_events_config = Some(Box::new(audio_events));
+        }
+        let serialised_msg = serde_json::to_string(&message)?;
+        self.send_message(Frame::Text(serialised_msg)).await
+    }
+
+    async fn set_recognition_config(&mut self, config: models::TranscriptionConfig) -> Result<()> {
+        let message = models::SetRecognitionConfig::new(
+            models::set_recognition_config::Message::SetRecognitionConfig,
+            config,
+        );
         let serialised_msg = serde_json::to_string(&message)?;
-        let ws_message = Message::from(serialised_msg);
-        self.send_message(ws_message).await
+        self.send_message(Frame::Text(serialised_msg)).await
+    }
+
+    async fn force_end_of_utterance(&mut self) -> Result<()> {
+        let message = models::ForceEndOfUtterance::new(
+            models::force_end_of_utterance::Message::ForceEndOfUtterance,
+        );
+        let serialised_msg = serde_json::to_string(&message)?;
+        self.send_message(Frame::Text(serialised_msg)).await
     }
 
     async fn send_close(&mut self, last_seq_no: i32) -> Result<()> {
@@ -472,7 +1235,241 @@ impl SenderWrapper {
             models::EndOfStream::new(last_seq_no, models::end_of_stream::Message::EndOfStream);
         let serialised_msg = serde_json::to_string(&message)?;
         println!("{}", serialised_msg);
-        let tungstenite_msg = Message::from(serialised_msg);
-        self.send_message(tungstenite_msg).await
+        self.send_message(Frame::Text(serialised_msg)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_known_message() {
+        let data = br#"{"message": "AudioAdded", "seq_no": 3}"#;
+        match from_slice::<ReadMessage>(data).unwrap() {
+            ReadMessage::AudioAdded(mess) => assert_eq!(mess.seq_no, 3),
+            mess => panic!("Decoded the wrong message {:?}", mess),
+        }
+
+        let data =
+            br#"{"message": "EndOfUtterance", "metadata": {"start_time": 1.5, "end_time": 1.5}}"#;
+        match from_slice::<ReadMessage>(data).unwrap() {
+            ReadMessage::EndOfUtterance(mess) => assert_eq!(mess.metadata.end_time, Some(1.5)),
+            mess => panic!("Decoded the wrong message {:?}", mess),
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown_message() {
+        let data = br#"{"message": "SomethingNew", "value": 1}"#;
+        match from_slice::<ReadMessage>(data).unwrap() {
+            ReadMessage::Unknown { message, raw } => {
+                assert_eq!(message, "SomethingNew");
+                assert_eq!(raw["value"], 1);
+            }
+            mess => panic!("Decoded the wrong message {:?}", mess),
+        }
+
+        // a known message with a missing field is an error rather than being passed on as Unknown
+        let data = br#"{"message": "AudioAdded"}"#;
+        assert!(from_slice::<ReadMessage>(data).is_err());
+        let data = br#"{"message": "Error", "type": "invalid_model", "reason": 1}"#;
+        assert!(from_slice::<ReadMessage>(data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_over_loopback() {
+        let (transport, mut server) = loopback();
+        let (mut rt_session, mut messages) =
+            RealtimeSession::with_transport("token".to_owned(), None, transport).unwrap();
+
+        let server_task = tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            let mut seq_no = 0;
+            while let Some(frame) = connection.recv().await {
+                let reply = match frame {
+                    Frame::Binary(_) => {
+                        seq_no += 1;
+                        format!(r#"{{"message": "AudioAdded", "seq_no": {}}}"#, seq_no)
+                    }
+                    Frame::Text(text) if text.contains("StartRecognition") => {
+                        r#"{"message": "RecognitionStarted"}"#.to_owned()
+                    }
+                    Frame::Text(text) if text.contains("EndOfStream") => {
+                        r#"{"message": "EndOfTranscript"}"#.to_owned()
+                    }
+                    _ => continue,
+                };
+                connection.send(Frame::Text(reply)).unwrap();
+            }
+            seq_no
+        });
+
+        // aborting while no run is in progress does not abort the next one
+        rt_session.control().abort();
+        let audio = std::io::Cursor::new(vec![0u8; 20000]);
+        rt_session
+            .run(SessionConfig::default(), audio)
+            .await
+            .unwrap();
+        assert_eq!(server_task.await.unwrap(), 3);
+
+        assert!(matches!(
+            messages.recv().await,
+            Some(ReadMessage::RecognitionStarted(_))
+        ));
+        let mut last = None;
+        while let Ok(message) = messages.try_recv() {
+            last = Some(message);
+        }
+        assert!(matches!(last, Some(ReadMessage::EndOfTranscript(_))));
+
+        // once run has returned, commands fail rather than waiting for a run which may never come
+        let control = rt_session.control();
+        let stop = time::timeout(
+            time::Duration::from_secs(1),
+            control.stop(time::Duration::from_secs(5)),
+        );
+        assert!(matches!(stop.await, Ok(Err(RealtimeError::Closed(_)))));
+    }
+
+    #[tokio::test]
+    async fn test_retry_full_send_queue() {
+        let (mut transport, mut server) = loopback();
+        transport.set_max_send_queue(Some(1));
+
+        // without retries, audio sent while the server is not reading fails the session
+        let (mut rt_session, _messages) =
+            RealtimeSession::with_transport("token".to_owned(), None, transport.clone()).unwrap();
+        rt_session.set_retry_policy(RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        });
+        let server_task = tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            connection.recv().await.unwrap();
+            let started = r#"{"message": "RecognitionStarted"}"#.to_owned();
+            connection.send(Frame::Text(started)).unwrap();
+            (server, connection)
+        });
+        let audio = std::io::Cursor::new(vec![0u8; 20000]);
+        let err = rt_session
+            .run(SessionConfig::default(), audio)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            RealtimeError::Transport(err) if matches!(*err, tokio_tungstenite::tungstenite::Error::SendQueueFull(_))
+        ));
+        let (mut server, _stalled) = server_task.await.unwrap();
+
+        // with retries, the audio goes through once the server catches up
+        let (mut rt_session, _messages) =
+            RealtimeSession::with_transport("token".to_owned(), None, transport).unwrap();
+        rt_session.set_retry_policy(RetryPolicy {
+            initial_backoff: time::Duration::from_millis(10),
+            ..RetryPolicy::default()
+        });
+        let server_task = tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            connection.recv().await.unwrap();
+            let started = r#"{"message": "RecognitionStarted"}"#.to_owned();
+            connection.send(Frame::Text(started)).unwrap();
+            time::sleep(time::Duration::from_millis(20)).await;
+            let mut seq_no = 0;
+            while let Some(frame) = connection.recv().await {
+                let reply = match frame {
+                    Frame::Binary(_) => {
+                        seq_no += 1;
+                        format!(r#"{{"message": "AudioAdded", "seq_no": {}}}"#, seq_no)
+                    }
+                    Frame::Text(text) if text.contains("EndOfStream") => {
+                        r#"{"message": "EndOfTranscript"}"#.to_owned()
+                    }
+                    _ => continue,
+                };
+                connection.send(Frame::Text(reply)).unwrap();
+            }
+            seq_no
+        });
+        let audio = std::io::Cursor::new(vec![0u8; 20000]);
+        rt_session
+            .run(SessionConfig::default(), audio)
+            .await
+            .unwrap();
+        assert_eq!(server_task.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stop_mid_chunk() {
+        let (transport, mut server) = loopback();
+        let (mut rt_session, _messages) =
+            RealtimeSession::with_transport("token".to_owned(), None, transport).unwrap();
+
+        let server_task = tokio::spawn(async move {
+            let mut connection = server.accept().await.unwrap();
+            let mut received = vec![];
+            let mut seq_no = 0;
+            while let Some(frame) = connection.recv().await {
+                let reply = match frame {
+                    Frame::Binary(data) => {
+                        received.push(format!("audio {}", data.len()));
+                        seq_no += 1;
+                        format!(r#"{{"message": "AudioAdded", "seq_no": {}}}"#, seq_no)
+                    }
+                    Frame::Text(text) if text.contains("StartRecognition") => {
+                        r#"{"message": "RecognitionStarted"}"#.to_owned()
+                    }
+                    Frame::Text(text) if text.contains("ForceEndOfUtterance") => {
+                        received.push("ForceEndOfUtterance".to_owned());
+                        continue;
+                    }
+                    Frame::Text(text) if text.contains("EndOfStream") => {
+                        received.push("EndOfStream".to_owned());
+                        r#"{"message": "EndOfTranscript"}"#.to_owned()
+                    }
+                    _ => continue,
+                };
+                connection.send(Frame::Text(reply)).unwrap();
+            }
+            received
+        });
+
+        // 100ms chunks of 16kHz pcm_s16le are 3200 bytes, so none of the audio written fills a chunk
+        let mut audio_format = models::AudioFormat::new(models::audio_format::Type::Raw);
+        audio_format.encoding = Some(models::audio_format::Encoding::PcmS16le);
+        audio_format.sample_rate = Some(16000);
+        let config = SessionConfig {
+            audio_format: Some(audio_format),
+            chunk_duration_ms: Some(100),
+            ..SessionConfig::default()
+        };
+
+        let (reader, mut writer) = tokio::io::duplex(8192);
+        let control = rt_session.control();
+        let driver = tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            writer.write_all(&[0; 1001]).await.unwrap();
+            time::sleep(time::Duration::from_millis(50)).await;
+            control.force_end_of_utterance().await.unwrap();
+            writer.write_all(&[0; 601]).await.unwrap();
+            time::sleep(time::Duration::from_millis(50)).await;
+            control.stop(time::Duration::from_secs(5)).await.unwrap();
+            // the reader stalls rather than ending, so only stop closes the stream
+            drop(writer);
+        });
+
+        rt_session.run(config, reader).await.unwrap();
+        driver.await.unwrap();
+        // the audio buffered is sent before ForceEndOfUtterance and EndOfStream, with the odd byte carried over
+        assert_eq!(
+            server_task.await.unwrap(),
+            vec![
+                "audio 1000",
+                "ForceEndOfUtterance",
+                "audio 602",
+                "EndOfStream"
+            ]
+        );
     }
 }
@@ -0,0 +1,31 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct AudioEventEndData {
+    #[serde(rename = "type")]
+    pub type_value: String,
+    #[serde(rename = "end_time")]
+    pub end_time: f32,
+}
+
+impl AudioEventEndData {
+    pub fn new(type_value: String, end_time: f32) -> AudioEventEndData {
+        AudioEventEndData {
+            type_value,
+            end_time,
+        }
+    }
+}
+
+
@@ -0,0 +1,34 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct AudioEventStartData {
+    #[serde(rename = "type")]
+    pub type_value: String,
+    #[serde(rename = "start_time")]
+    pub start_time: f32,
+    #[serde(rename = "confidence")]
+    pub confidence: f32,
+}
+
+impl AudioEventStartData {
+    pub fn new(type_value: String, start_time: f32, confidence: f32) -> AudioEventStartData {
+        AudioEventStartData {
+            type_value,
+            start_time,
+            confidence,
+        }
+    }
+}
+
+
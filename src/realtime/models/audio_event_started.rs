@@ -0,0 +1,43 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct AudioEventStarted {
+    #[serde(rename = "message")]
+    pub message: Message,
+    #[serde(rename = "event")]
+    pub event: Box<crate::realtime::models::AudioEventStartData>,
+}
+
+impl AudioEventStarted {
+    pub fn new(message: Message, event: crate::realtime::models::AudioEventStartData) -> AudioEventStarted {
+        AudioEventStarted {
+            message,
+            event: Box::new(event),
+        }
+    }
+}
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum Message {
+    #[serde(rename = "AudioEventStarted")]
+    AudioEventStarted,
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Self::AudioEventStarted
+    }
+}
+
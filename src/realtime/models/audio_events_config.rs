@@ -0,0 +1,29 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct AudioEventsConfig {
+    /// The audio event types to detect, e.g. \"music\", \"applause\" or \"laughter\". All types are detected if this is not set.
+    #[serde(rename = "types", skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+}
+
+impl AudioEventsConfig {
+    pub fn new() -> AudioEventsConfig {
+        AudioEventsConfig {
+            types: None,
+        }
+    }
+}
+
+
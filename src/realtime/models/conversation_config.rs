@@ -0,0 +1,29 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct ConversationConfig {
+    /// How long, in seconds, the speaker must be silent before the server sends an EndOfUtterance message. 0 disables end of utterance detection.
+    #[serde(rename = "end_of_utterance_silence_trigger", skip_serializing_if = "Option::is_none")]
+    pub end_of_utterance_silence_trigger: Option<f32>,
+}
+
+impl ConversationConfig {
+    pub fn new() -> ConversationConfig {
+        ConversationConfig {
+            end_of_utterance_silence_trigger: None,
+        }
+    }
+}
+
+
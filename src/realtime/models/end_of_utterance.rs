@@ -0,0 +1,43 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct EndOfUtterance {
+    #[serde(rename = "message")]
+    pub message: Message,
+    #[serde(rename = "metadata")]
+    pub metadata: Box<crate::realtime::models::EndOfUtteranceMetadata>,
+}
+
+impl EndOfUtterance {
+    pub fn new(message: Message, metadata: crate::realtime::models::EndOfUtteranceMetadata) -> EndOfUtterance {
+        EndOfUtterance {
+            message,
+            metadata: Box::new(metadata),
+        }
+    }
+}
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum Message {
+    #[serde(rename = "EndOfUtterance")]
+    EndOfUtterance,
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Self::EndOfUtterance
+    }
+}
+
@@ -0,0 +1,31 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct EndOfUtteranceMetadata {
+    #[serde(rename = "start_time", skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<f32>,
+    #[serde(rename = "end_time", skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<f32>,
+}
+
+impl EndOfUtteranceMetadata {
+    pub fn new() -> EndOfUtteranceMetadata {
+        EndOfUtteranceMetadata {
+            start_time: None,
+            end_time: None,
+        }
+    }
+}
+
+
@@ -0,0 +1,40 @@
+/*
+ * OpenAPI Template
+ *
+ * No description provided (generated by Openapi Generator https://github.com/openapitools/openapi-generator)
+ *
+ * The version of the OpenAPI document: 1.0.0
+ * 
+ * Generated by: https://openapi-generator.tech
+ */
+
+
+
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct ForceEndOfUtterance {
+    #[serde(rename = "message")]
+    pub message: Message,
+}
+
+impl ForceEndOfUtterance {
+    pub fn new(message: Message) -> ForceEndOfUtterance {
+        ForceEndOfUtterance {
+            message,
+        }
+    }
+}
+
+/// 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum Message {
+    #[serde(rename = "ForceEndOfUtterance")]
+    ForceEndOfUtterance,
+}
+
+impl Default for Message {
+    fn default() -> Message {
+        Self::ForceEndOfUtterance
+    }
+}
+
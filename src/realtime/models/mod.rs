@@ -8,16 +8,34 @@ pub mod add_translation;
 pub use self::add_translation::AddTranslation;
 pub mod audio_added;
 pub use self::audio_added::AudioAdded;
+pub mod audio_event_end_data;
+pub use self::audio_event_end_data::AudioEventEndData;
+pub mod audio_event_ended;
+pub use self::audio_event_ended::AudioEventEnded;
+pub mod audio_event_start_data;
+pub use self::audio_event_start_data::AudioEventStartData;
+pub mod audio_event_started;
+pub use self::audio_event_started::AudioEventStarted;
+pub mod audio_events_config;
+pub use self::audio_events_config::AudioEventsConfig;
 pub mod audio_format;
 pub use self::audio_format::AudioFormat;
+pub mod conversation_config;
+pub use self::conversation_config::ConversationConfig;
 pub mod diarization_config;
 pub use self::diarization_config::DiarizationConfig;
 pub mod end_of_stream;
 pub use self::end_of_stream::EndOfStream;
 pub mod end_of_transcript;
 pub use self::end_of_transcript::EndOfTranscript;
+pub mod end_of_utterance;
+pub use self::end_of_utterance::EndOfUtterance;
+pub mod end_of_utterance_metadata;
+pub use self::end_of_utterance_metadata::EndOfUtteranceMetadata;
 pub mod error;
 pub use self::error::Error;
+pub mod force_end_of_utterance;
+pub use self::force_end_of_utterance::ForceEndOfUtterance;
 pub mod info;
 pub use self::info::Info;
 pub mod max_delay_mode_config;
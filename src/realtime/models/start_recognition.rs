@@ -13,6 +13,8 @@
 
 #[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
 pub struct StartRecognition {
+    #[serde(rename = "audio_events_config", skip_serializing_if = "Option::is_none")]
+    pub audio_events_config: Option<Box<crate::realtime::models::AudioEventsConfig>>,
     #[serde(rename = "audio_format")]
     pub audio_format: Box<crate::realtime::models::AudioFormat>,
     #[serde(rename = "message")]
@@ -26,6 +28,7 @@ pub struct StartRecognition {
 impl StartRecognition {
     pub fn new(audio_format: crate::realtime::models::AudioFormat, message: Message, transcription_config: crate::realtime::models::TranscriptionConfig) -> StartRecognition {
         StartRecognition {
+            audio_events_config: None,
             audio_format: Box::new(audio_format),
             message,
             transcription_config: Box::new(transcription_config),
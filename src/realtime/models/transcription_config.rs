@@ -15,6 +15,8 @@
 pub struct TranscriptionConfig {
     #[serde(rename = "additional_vocab", skip_serializing_if = "Option::is_none")]
     pub additional_vocab: Option<Vec<crate::realtime::models::VocabWord>>,
+    #[serde(rename = "conversation_config", skip_serializing_if = "Option::is_none")]
+    pub conversation_config: Option<Box<crate::realtime::models::ConversationConfig>>,
     #[serde(rename = "diarization", skip_serializing_if = "Option::is_none")]
     pub diarization: Option<crate::realtime::models::DiarizationConfig>,
     /// Request a specialized model based on 'language' but optimized for a particular field, e.g. \"finance\" or \"medical\".
@@ -46,6 +48,7 @@ impl TranscriptionConfig {
     pub fn new(language: String) -> TranscriptionConfig {
         TranscriptionConfig {
             additional_vocab: None,
+            conversation_config: None,
             diarization: None,
             domain: None,
             enable_entities: None,
@@ -0,0 +1,34 @@
+//! Timeouts and keepalive settings for the websocket connection of a realtime session.
+use std::time::Duration;
+
+/// Configures how long a RealtimeSession waits at each stage of a session, set with RealtimeSession::set_options.
+///
+/// Every timeout fails the session with RealtimeError::Timeout and a TimeoutKind saying which stage took too long,
+/// e.g. to tell a server which never answers StartRecognition apart from a network which is slow to connect.
+/// A timeout of None means waiting for as long as it takes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RealtimeOptions {
+    /// How long to wait for the TCP, TLS and websocket handshakes. Defaults to 10 seconds.
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for RecognitionStarted after sending StartRecognition. Defaults to 10 seconds.
+    pub start_timeout: Option<Duration>,
+    /// How long to wait for the next frame from the server once the session has started. Defaults to None.
+    /// Pong frames count, so with a ping_interval set this also catches a server which has stopped responding while no audio is sent.
+    pub idle_timeout: Option<Duration>,
+    /// How often to send a websocket ping to keep the connection alive. Defaults to None, which sends no pings.
+    pub ping_interval: Option<Duration>,
+    /// The maximum duration of the whole session, including any reconnects. Defaults to None.
+    pub max_duration: Option<Duration>,
+}
+
+impl Default for RealtimeOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Some(Duration::from_secs(10)),
+            start_timeout: Some(Duration::from_secs(10)),
+            idle_timeout: None,
+            ping_interval: None,
+            max_duration: None,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Sends audio at a steady rate relative to wall-clock time, so file-backed sessions behave like live input.
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When a chunk is due if the wait is too long to represent, the same horizon tokio uses for a timer which never fires
+const FAR_FUTURE: Duration = Duration::from_secs(86400 * 365 * 30);
+
+/// Works out when the next audio chunk is due, based on how much audio has already been sent
+pub(crate) struct Pacer {
+    bytes_per_second: f64,
+    started: Option<Instant>,
+    bytes_sent: u64,
+}
+
+impl Pacer {
+    /// Creates a pacer which sends audio at speed times real time, or None if pacing is not possible
+    pub(crate) fn new(speed: f32, bytes_per_second: u32) -> Option<Self> {
+        if !is_valid_speed(speed) || bytes_per_second == 0 {
+            return None;
+        }
+        Some(Self {
+            bytes_per_second: bytes_per_second as f64 * speed as f64,
+            started: None,
+            bytes_sent: 0,
+        })
+    }
+
+    /// Records a chunk of audio that has just been sent
+    pub(crate) fn sent(&mut self, len: usize) {
+        self.started.get_or_insert_with(Instant::now);
+        self.bytes_sent += len as u64;
+    }
+
+    /// Returns the time the next chunk is due, if that is in the future
+    pub(crate) fn next_send_at(&self) -> Option<Instant> {
+        let started = self.started?;
+        let due = Duration::try_from_secs_f64(self.bytes_sent as f64 / self.bytes_per_second)
+            .ok()
+            .and_then(|wait| started.checked_add(wait))
+            .unwrap_or_else(|| Instant::now() + FAR_FUTURE);
+        (due > Instant::now()).then_some(due)
+    }
+}
+
+/// Whether a speed can be used to pace audio or replay a session, ruling out zero, negative, NaN and infinite speeds
+pub(crate) fn is_valid_speed(speed: f32) -> bool {
+    speed.is_finite() && speed > 0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer_real_time() {
+        // 16kHz pcm_s16le at double speed
+        let mut pacer = Pacer::new(2.0, 32000).unwrap();
+        assert!(pacer.next_send_at().is_none());
+
+        pacer.sent(6400);
+        let wait = pacer.next_send_at().unwrap() - Instant::now();
+        assert!(wait <= Duration::from_millis(100));
+        assert!(wait > Duration::from_millis(50));
+
+        assert!(Pacer::new(0.0, 32000).is_none());
+        assert!(Pacer::new(f32::NAN, 32000).is_none());
+        assert!(Pacer::new(f32::INFINITY, 32000).is_none());
+
+        // a subnormal speed is valid, but the next chunk is so far off it is never due
+        let mut pacer = Pacer::new(f32::MIN_POSITIVE / 2.0, 32000).unwrap();
+        pacer.sent(6400);
+        assert!(pacer.next_send_at().unwrap() > Instant::now() + Duration::from_secs(86400));
+    }
+}
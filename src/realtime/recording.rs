@@ -0,0 +1,396 @@
+//! Recording of realtime sessions into an archive file, and playback of archives for offline debugging and regression tests.
+//!
+//! An archive is a JSON Lines file. The first record of every run holds the SessionConfig, followed by a record for
+//! every chunk of audio sent and every message received, each with the time in seconds since the run started.
+use base64::{engine::general_purpose, Engine as _};
+use futures::{stream, stream::BoxStream, StreamExt};
+use log::warn;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread::{self, JoinHandle};
+use tokio::time;
+
+use super::error::{RealtimeError, Result};
+use super::pacing::is_valid_speed;
+use super::{ReadMessage, SessionConfig};
+
+/// A single line of a session archive.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    /// The config a run of the session was started with
+    Config {
+        /// The config of the session
+        config: SessionConfig,
+    },
+    /// A chunk of audio read from the audio source and sent to the server
+    Audio {
+        /// The sequence number of the chunk for the session, counting from 1 for each run, as used in AudioAdded.
+        /// A replayed chunk has the same sequence number as when it was first sent
+        seq_no: i32,
+        /// When the chunk was sent, in seconds since the run started
+        time: f64,
+        /// The audio, base64 encoded
+        data: String,
+        /// Whether the chunk was sent again after a reconnect, rather than read from the audio source
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        replayed: bool,
+    },
+    /// A message received from the server, as it was passed on by the session
+    Message {
+        /// When the message was received, in seconds since the run started
+        time: f64,
+        /// The message
+        message: ReadMessage,
+    },
+}
+
+/// Writes a session archive as the session runs, set with RealtimeSession::set_recorder.
+///
+/// Records are written by a thread of their own, so a slow disk does not hold up the session. When the recorder is
+/// dropped, it waits for the records still queued to be written. Recording never fails the session.
+/// If the archive cannot be written, the error is logged and the session carries on.
+pub struct SessionRecorder {
+    /// When the current run started
+    started: Mutex<time::Instant>,
+    sender: Option<mpsc::Sender<Record>>,
+    writer_thread: Option<JoinHandle<()>>,
+}
+
+impl SessionRecorder {
+    /// Creates a recorder which writes the archive to a new file at path, replacing any file already there
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    /// Creates a recorder which writes the archive to any writer, e.g. a Vec in tests
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let writer_thread = thread::spawn(move || write_records(writer, receiver));
+        Self {
+            started: Mutex::new(time::Instant::now()),
+            sender: Some(sender),
+            writer_thread: Some(writer_thread),
+        }
+    }
+
+    /// Marks the start of a run of the session
+    pub(crate) fn start(&self, config: &SessionConfig) {
+        *self.started.lock().unwrap() = time::Instant::now();
+        self.send(Record::Config {
+            config: config.clone(),
+        });
+    }
+
+    /// Records a chunk of audio sent to the server, either read from the audio source or replayed after a reconnect
+    pub(crate) fn audio(&self, seq_no: i32, data: &[u8], replayed: bool) {
+        self.send(Record::Audio {
+            seq_no,
+            time: self.elapsed(),
+            data: general_purpose::STANDARD.encode(data),
+            replayed,
+        });
+    }
+
+    pub(crate) fn message(&self, message: &ReadMessage) {
+        self.send(Record::Message {
+            time: self.elapsed(),
+            message: message.clone(),
+        });
+    }
+
+    /// Seconds since the current run started
+    fn elapsed(&self) -> f64 {
+        self.started.lock().unwrap().elapsed().as_secs_f64()
+    }
+
+    fn send(&self, record: Record) {
+        if let Some(sender) = &self.sender {
+            // the writer thread only stops once the sender is dropped, so this cannot fail
+            let _ = sender.send(record);
+        }
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer_thread) = self.writer_thread.take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
+
+/// Writes records until the recorder is dropped, flushing whenever it has caught up
+fn write_records<W: Write>(mut writer: W, receiver: mpsc::Receiver<Record>) {
+    while let Ok(record) = receiver.recv() {
+        let mut res = write_record(&mut writer, &record);
+        for record in receiver.try_iter() {
+            res = res.and_then(|_| write_record(&mut writer, &record));
+        }
+        if let Err(err) = res.and_then(|_| writer.flush()) {
+            warn!("Failed to write to the session archive: {}", err);
+        }
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+/// Plays back a session archive written by a SessionRecorder.
+///
+/// If the archive holds several runs of a session, only the first is played back.
+///
+/// # Example
+///
+/// ```
+/// let replay = ReplaySession::open("session.jsonl").unwrap();
+/// let mut messages = replay.stream(Some(1.0));
+/// while let Some(message) = messages.next().await {
+///     println!("{:?}", message.unwrap());
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ReplaySession {
+    config: SessionConfig,
+    audio: Vec<Vec<u8>>,
+    messages: Vec<(f64, ReadMessage)>,
+}
+
+impl ReplaySession {
+    /// Reads the archive at path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads an archive from any reader
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut config = None;
+        let mut audio = vec![];
+        let mut messages = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Record>(&line)? {
+                Record::Config { .. } if config.is_some() => break,
+                Record::Config { config: run_config } => config = Some(run_config),
+                Record::Audio { replayed: true, .. } => {}
+                Record::Audio { data, .. } => {
+                    let data = general_purpose::STANDARD
+                        .decode(data)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    audio.push(data);
+                }
+                Record::Message { time, message } => messages.push((time, message)),
+            }
+        }
+        let config = config.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the archive does not start with a config record",
+            )
+        })?;
+        Ok(Self {
+            config,
+            audio,
+            messages,
+        })
+    }
+
+    /// The config the session was run with
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    /// The chunks of audio read from the audio source and sent to the server, in order, not counting any replayed
+    pub fn audio_chunks(&self) -> &[Vec<u8>] {
+        &self.audio
+    }
+
+    /// All of the audio as a reader, which can be passed to RealtimeSession::run to run the session again against a server
+    pub fn audio_reader(&self) -> io::Cursor<Vec<u8>> {
+        io::Cursor::new(self.audio.concat())
+    }
+
+    /// The messages received from the server, in order
+    pub fn messages(&self) -> impl Iterator<Item = &ReadMessage> {
+        self.messages.iter().map(|(_, message)| message)
+    }
+
+    /// Plays the messages back as a stream of the same shape as RealtimeSession::run_stream.
+    ///
+    /// With a speed, each message is delayed until its original receive time, divided by the speed,
+    /// e.g. 1.0 for the original timing or 2.0 for twice as fast. Without one, the messages are yielded straight away.
+    /// A speed which is not a positive, finite number yields a single RealtimeError::Config.
+    pub fn stream(self, speed: Option<f32>) -> BoxStream<'static, Result<ReadMessage>> {
+        let messages = stream::iter(self.messages);
+        match speed {
+            None => messages.map(|(_, message)| Ok(message)).boxed(),
+            Some(speed) if !is_valid_speed(speed) => stream::once(async move {
+                Err(RealtimeError::Config(format!(
+                    "A replay speed must be positive and finite, not {}",
+                    speed
+                )))
+            })
+            .boxed(),
+            Some(speed) => {
+                let started = time::Instant::now();
+                messages
+                    .then(move |(at, message)| async move {
+                        let due = time::Duration::try_from_secs_f64(at.max(0.0) / speed as f64)
+                            .ok()
+                            .and_then(|offset| started.checked_add(offset));
+                        match due {
+                            Some(due) => time::sleep_until(due).await,
+                            // a message too far off to represent is never due
+                            None => std::future::pending().await,
+                        }
+                        Ok(message)
+                    })
+                    .boxed()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::realtime::{loopback, models, Frame, RealtimeSession, ReconnectPolicy};
+    use std::sync::Arc;
+
+    /// A writer whose contents can still be read once the recorder has taken it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let buffer = SharedBuffer::default();
+        let recorder = SessionRecorder::new(buffer.clone());
+        recorder.start(&SessionConfig::default());
+        recorder.audio(1, &[1, 2, 3], false);
+        recorder.audio(1, &[1, 2, 3], true);
+        let message =
+            serde_json::from_str::<ReadMessage>(r#"{"message": "AudioAdded", "seq_no": 1}"#)
+                .unwrap();
+        recorder.message(&message);
+        // a second run is not played back
+        recorder.start(&SessionConfig::default());
+        recorder.audio(1, &[4], false);
+        drop(recorder);
+
+        let archive = buffer.0.lock().unwrap().clone();
+        let replay = ReplaySession::read(&archive[..]).unwrap();
+        assert_eq!(replay.config(), &SessionConfig::default());
+        assert_eq!(replay.audio_chunks(), &[vec![1, 2, 3]]);
+        let messages: Vec<ReadMessage> = replay
+            .stream(Some(100.0))
+            .map(|message| message.unwrap())
+            .collect()
+            .await;
+        assert!(matches!(
+            messages.as_slice(),
+            [ReadMessage::AudioAdded(mess)] if mess.seq_no == 1
+        ));
+
+        let mut invalid = ReplaySession::read(&archive[..])
+            .unwrap()
+            .stream(Some(f32::NAN));
+        assert!(matches!(
+            invalid.next().await,
+            Some(Err(RealtimeError::Config(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_record_reconnect() {
+        let (transport, mut server) = loopback();
+        let (mut rt_session, _messages) =
+            RealtimeSession::with_transport("token".to_owned(), None, transport).unwrap();
+        rt_session.set_reconnect_policy(Some(ReconnectPolicy {
+            initial_backoff: time::Duration::from_millis(10),
+            ..ReconnectPolicy::default()
+        }));
+        let buffer = SharedBuffer::default();
+        rt_session.set_recorder(Some(SessionRecorder::new(buffer.clone())));
+
+        // the first connection acknowledges one chunk of three and then drops, and the second acknowledges the rest
+        let server_task = tokio::spawn(async move {
+            for acks in [1, 2] {
+                let mut connection = server.accept().await.unwrap();
+                while let Some(frame) = connection.recv().await {
+                    let reply = match frame {
+                        Frame::Text(text) if text.contains("StartRecognition") => {
+                            r#"{"message": "RecognitionStarted"}"#.to_owned()
+                        }
+                        Frame::Text(text) if text.contains("EndOfStream") => {
+                            for seq_no in 1..=acks {
+                                let ack =
+                                    format!(r#"{{"message": "AudioAdded", "seq_no": {}}}"#, seq_no);
+                                connection.send(Frame::Text(ack)).unwrap();
+                            }
+                            if acks == 1 {
+                                break;
+                            }
+                            r#"{"message": "EndOfTranscript"}"#.to_owned()
+                        }
+                        _ => continue,
+                    };
+                    connection.send(Frame::Text(reply)).unwrap();
+                }
+            }
+        });
+
+        let mut audio_format = models::AudioFormat::new(models::audio_format::Type::Raw);
+        audio_format.encoding = Some(models::audio_format::Encoding::PcmS16le);
+        audio_format.sample_rate = Some(16000);
+        let config = SessionConfig {
+            audio_format: Some(audio_format),
+            chunk_duration_ms: Some(100),
+            ..SessionConfig::default()
+        };
+        let audio = std::io::Cursor::new(vec![0u8; 9600]);
+        rt_session.run(config, audio).await.unwrap();
+        server_task.await.unwrap();
+        rt_session.set_recorder(None);
+
+        let archive = buffer.0.lock().unwrap().clone();
+        let mut audio = vec![];
+        let mut acks = vec![];
+        for line in archive.lines() {
+            match serde_json::from_str::<Record>(&line.unwrap()).unwrap() {
+                Record::Audio {
+                    seq_no, replayed, ..
+                } => audio.push((seq_no, replayed)),
+                Record::Message {
+                    message: ReadMessage::AudioAdded(mess),
+                    ..
+                } => acks.push(mess.seq_no),
+                _ => {}
+            }
+        }
+        // the replayed chunks keep the sequence numbers the acknowledgements refer to
+        assert_eq!(
+            audio,
+            vec![(1, false), (2, false), (3, false), (2, true), (3, true)]
+        );
+        assert_eq!(acks, vec![1, 2, 3]);
+    }
+}
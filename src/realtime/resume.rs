@@ -0,0 +1,246 @@
+//! Support for resuming a realtime session after the connection drops, by replaying audio the server has not yet acknowledged.
+use log::warn;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::{models, ReadMessage};
+
+/// Configures the opt-in resilient mode of a RealtimeSession, set with RealtimeSession::set_reconnect_policy.
+///
+/// When the websocket drops mid-session, the session reconnects with exponential backoff, sends StartRecognition again,
+/// replays every audio chunk the server had not yet acknowledged with AudioAdded and then carries on reading audio.
+/// Timestamps and sequence numbers of the messages received after a reconnect are shifted so that they line up with
+/// the audio sent on the earlier connections.
+///
+/// Resuming only works with raw audio, where the encoding and sample_rate of the AudioFormat are set,
+/// as these are needed to work out how much audio the server had already processed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    /// The maximum number of consecutive reconnect attempts before giving up. This resets once a reconnect succeeds.
+    pub max_attempts: u32,
+    /// The delay before the first reconnect attempt. This doubles after every failed attempt.
+    pub initial_backoff: Duration,
+    /// The upper bound on the delay between reconnect attempts.
+    pub max_backoff: Duration,
+    /// The maximum number of bytes of unacknowledged audio to keep for replay.
+    /// If the server falls further behind than this, the oldest audio is dropped and will be missing from the transcript.
+    pub max_buffer_bytes: usize,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(10),
+            max_buffer_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay to wait before the given reconnect attempt, counting from 1
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// Ring buffer of the audio chunks that have been sent but not yet acknowledged by the server.
+///
+/// Sequence numbers restart from 1 on every connection, so the buffer keeps track of the offset between
+/// the sequence numbers of the current connection and those of the whole session.
+#[derive(Default)]
+pub(crate) struct ReplayBuffer {
+    enabled: bool,
+    max_bytes: usize,
+    chunks: VecDeque<(i32, Vec<u8>)>,
+    buffered_bytes: usize,
+    /// Bytes of audio which came before the first chunk in the buffer, either acknowledged or dropped
+    bytes_before: u64,
+    /// The number of chunks pushed over the whole session
+    last_seq_no: i32,
+    /// The session sequence number of the chunk sent just before the current connection started
+    seq_offset: i32,
+}
+
+impl ReplayBuffer {
+    pub(crate) fn new(policy: Option<&ReconnectPolicy>) -> Self {
+        Self {
+            enabled: policy.is_some(),
+            max_bytes: policy.map(|p| p.max_buffer_bytes).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Stores a chunk before it is sent to the server, returning its sequence number for the session as a whole
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> i32 {
+        self.last_seq_no += 1;
+        if !self.enabled {
+            return self.last_seq_no;
+        }
+        self.chunks.push_back((self.last_seq_no, chunk.to_vec()));
+        self.buffered_bytes += chunk.len();
+        while self.buffered_bytes > self.max_bytes {
+            if let Some((seq_no, dropped)) = self.chunks.pop_front() {
+                warn!(
+                    "Replay buffer is full, dropping unacknowledged audio chunk {}",
+                    seq_no
+                );
+                self.buffered_bytes -= dropped.len();
+                self.bytes_before += dropped.len() as u64;
+            } else {
+                break;
+            }
+        }
+        self.last_seq_no
+    }
+
+    /// Drops every chunk up to and including the given sequence number of the current connection.
+    /// Returns the equivalent sequence number for the session as a whole.
+    pub(crate) fn acknowledge(&mut self, seq_no: i32) -> i32 {
+        let session_seq_no = seq_no + self.seq_offset;
+        while let Some((chunk_seq_no, _)) = self.chunks.front() {
+            if *chunk_seq_no > session_seq_no {
+                break;
+            }
+            if let Some((_, chunk)) = self.chunks.pop_front() {
+                self.buffered_bytes -= chunk.len();
+                self.bytes_before += chunk.len() as u64;
+            }
+        }
+        session_seq_no
+    }
+
+    /// Prepares the buffer for a new connection and returns the chunks to send again, oldest first,
+    /// along with their sequence numbers for the session.
+    ///
+    /// Unless audio was dropped because the buffer was full, the first chunk to replay is the one after the last acknowledged chunk.
+    /// The chunks stay in the buffer until they are acknowledged on the new connection.
+    pub(crate) fn resume(&mut self) -> Vec<(i32, Vec<u8>)> {
+        self.seq_offset = self
+            .chunks
+            .front()
+            .map(|(seq_no, _)| seq_no - 1)
+            .unwrap_or(self.last_seq_no);
+        self.chunks.iter().cloned().collect()
+    }
+
+    /// Seconds of audio that came before the start of the current connection
+    pub(crate) fn time_offset(&self, bytes_per_second: u32) -> f32 {
+        if bytes_per_second == 0 {
+            return 0.0;
+        }
+        (self.bytes_before as f64 / bytes_per_second as f64) as f32
+    }
+}
+
+/// Shifts the timestamps of a message from a resumed connection, so they are relative to the start of the session
+pub(crate) fn shift_timestamps(message: &mut ReadMessage, offset: f32) {
+    if offset == 0.0 {
+        return;
+    }
+    map_timestamps(message, |time| time + offset);
+}
+
+/// Rewrites the times of the results, utterances and audio events in a message
+pub(crate) fn map_timestamps(message: &mut ReadMessage, map: impl Fn(f32) -> f32) {
+    let map_results = |metadata: &mut models::RecognitionMetadata,
+                       results: &mut [models::RecognitionResult]| {
+        metadata.start_time = map(metadata.start_time);
+        metadata.end_time = map(metadata.end_time);
+        for result in results.iter_mut() {
+            result.start_time = map(result.start_time);
+            result.end_time = map(result.end_time);
+        }
+    };
+    match message {
+        ReadMessage::AddTranscript(mess) => map_results(&mut mess.metadata, &mut mess.results),
+        ReadMessage::AddPartialTranscript(mess) => {
+            map_results(&mut mess.metadata, &mut mess.results)
+        }
+        ReadMessage::AddTranslation(mess) => {
+            for sentence in mess.results.iter_mut() {
+                sentence.start_time = map(sentence.start_time);
+                sentence.end_time = map(sentence.end_time);
+            }
+        }
+        ReadMessage::AddPartialTranslation(mess) => {
+            for sentence in mess.results.iter_mut() {
+                sentence.start_time = map(sentence.start_time);
+                sentence.end_time = map(sentence.end_time);
+            }
+        }
+        ReadMessage::EndOfUtterance(mess) => {
+            mess.metadata.start_time = mess.metadata.start_time.map(&map);
+            mess.metadata.end_time = mess.metadata.end_time.map(&map);
+        }
+        ReadMessage::AudioEventStarted(mess) => {
+            mess.event.start_time = map(mess.event.start_time);
+        }
+        ReadMessage::AudioEventEnded(mess) => mess.event.end_time = map(mess.event.end_time),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_buffer_resume() {
+        let policy = ReconnectPolicy::default();
+        let mut buffer = ReplayBuffer::new(Some(&policy));
+        for _ in 0..4 {
+            buffer.push(&[0u8; 3200]);
+        }
+        assert_eq!(buffer.acknowledge(2), 2);
+
+        let replay = buffer.resume();
+        assert_eq!(
+            replay.iter().map(|(seq_no, _)| *seq_no).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        // 16kHz pcm_s16le is 32000 bytes per second
+        assert_eq!(buffer.time_offset(32000), 0.2);
+
+        // the first replayed chunk is seq_no 1 on the new connection, and 3 for the session
+        assert_eq!(buffer.acknowledge(1), 3);
+        assert_eq!(buffer.push(&[0u8; 3200]), 5);
+        assert_eq!(buffer.acknowledge(3), 5);
+        assert!(buffer.resume().is_empty());
+    }
+
+    #[test]
+    fn test_shift_timestamps() {
+        let mut messages = [
+            r#"{"message": "EndOfUtterance", "metadata": {"start_time": 1.0, "end_time": 1.5}}"#,
+            r#"{"message": "AudioEventStarted", "event": {"type": "music", "start_time": 1.0, "confidence": 0.9}}"#,
+            r#"{"message": "AudioEventEnded", "event": {"type": "music", "end_time": 1.5}}"#,
+        ]
+        .map(|data| serde_json::from_str::<ReadMessage>(data).unwrap());
+        for message in messages.iter_mut() {
+            shift_timestamps(message, 2.0);
+        }
+        match &messages {
+            [ReadMessage::EndOfUtterance(utterance), ReadMessage::AudioEventStarted(started), ReadMessage::AudioEventEnded(ended)] =>
+            {
+                assert_eq!(utterance.metadata.start_time, Some(3.0));
+                assert_eq!(utterance.metadata.end_time, Some(3.5));
+                assert_eq!(started.event.start_time, 3.0);
+                assert_eq!(ended.event.end_time, 3.5);
+            }
+            messages => panic!("Decoded the wrong messages {:?}", messages),
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(250));
+        assert_eq!(policy.backoff(3), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(20), policy.max_backoff);
+    }
+}
@@ -0,0 +1,101 @@
+//! Retries for individual websocket sends which fail for a reason that is likely to pass, such as a full send queue.
+use rand::{thread_rng, Rng};
+use std::time::Duration;
+
+use tokio_tungstenite::tungstenite;
+
+use super::RealtimeError;
+
+/// Configures how a RealtimeSession retries sending a message to the server, set with RealtimeSession::set_retry_policy.
+///
+/// Only a full send queue is retried, as the message was not taken then. Any other failure, such as a timed out
+/// or interrupted write, may come after the message has been queued, so resending it could deliver it twice.
+/// Errors which mean the websocket has gone away are returned straight away, so they can be handled by the ReconnectPolicy instead.
+/// The delay between retries doubles every time, and is randomly shortened by up to jitter times its length
+/// so that many sessions which fail at once do not all retry at once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of times to retry a message after the first attempt fails. 0 disables retries.
+    pub max_retries: u32,
+    /// The delay before the first retry.
+    pub initial_backoff: Duration,
+    /// The upper bound on the delay between retries.
+    pub max_backoff: Duration,
+    /// The fraction of each delay which may be randomly taken off it, between 0 and 1. A jitter which is not finite is ignored.
+    pub jitter: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay to wait before the given retry, counting from 1
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if !self.jitter.is_finite() {
+            return backoff;
+        }
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f32(1.0 - thread_rng().gen_range(0.0..jitter))
+    }
+}
+
+/// Returns true if a failed send is worth retrying on the same connection
+pub(crate) fn is_transient(err: &RealtimeError) -> bool {
+    let err = match err {
+        RealtimeError::Transport(err) => err.as_ref(),
+        _ => return false,
+    };
+    // by the time an IO error is returned tungstenite has already queued the frame, so only a full queue is safe to resend
+    matches!(err, tungstenite::Error::SendQueueFull(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        let policy = RetryPolicy::default();
+        for _ in 0..20 {
+            let backoff = policy.backoff(2);
+            assert!(backoff <= Duration::from_millis(200));
+            assert!(backoff > Duration::from_millis(100));
+        }
+        assert!(policy.backoff(10) <= policy.max_backoff);
+
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        let policy = RetryPolicy {
+            jitter: f32::NAN,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+
+        assert!(!is_transient(&RealtimeError::connection_closed()));
+        assert!(!is_transient(&RealtimeError::from(tungstenite::Error::Io(
+            std::io::ErrorKind::Interrupted.into()
+        ))));
+        assert!(is_transient(&RealtimeError::from(
+            tungstenite::Error::SendQueueFull(tungstenite::Message::Binary(vec![]))
+        )));
+    }
+}
@@ -0,0 +1,140 @@
+//! A push-style audio input for realtime sessions, for sources which produce frames rather than implementing AsyncRead.
+use bytes::{Buf, Bytes};
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::error::Result;
+use super::RealtimeError;
+
+enum SinkMessage {
+    Frame(Bytes),
+    Finish,
+}
+
+/// A cloneable handle for pushing audio frames into a realtime session, created by RealtimeSession::audio_sink.
+///
+/// Without a chunk_duration_ms in the SessionConfig, each frame is sent to the server as its own AddAudio message, unless it
+/// is larger than the 8 KiB read buffer of the session, in which case it is split. With one, the frames are gathered into
+/// chunks of that duration instead. Calling finish ends the audio stream, which makes the session send EndOfStream once
+/// every frame pushed before it has been sent.
+#[derive(Clone, Debug)]
+pub struct AudioSink {
+    sender: UnboundedSender<SinkMessage>,
+    finished: Arc<AtomicBool>,
+}
+
+impl AudioSink {
+    /// Queues a frame of audio to be sent to the server.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if finish has already been called, or if the session has been dropped.
+    pub fn push(&self, frame: impl Into<Bytes>) -> Result<()> {
+        if self.finished.load(Ordering::SeqCst) {
+            return Err(sink_closed());
+        }
+        self.sender
+            .send(SinkMessage::Frame(frame.into()))
+            .map_err(|_| sink_closed())
+    }
+
+    /// Ends the audio stream. Any clones of this sink can no longer push audio.
+    ///
+    /// # Errors
+    ///
+    /// This function errors if finish has already been called, or if the session has been dropped.
+    pub fn finish(&self) -> Result<()> {
+        if self.finished.swap(true, Ordering::SeqCst) {
+            return Err(sink_closed());
+        }
+        self.sender
+            .send(SinkMessage::Finish)
+            .map_err(|_| sink_closed())
+    }
+}
+
+fn sink_closed() -> RealtimeError {
+    RealtimeError::Closed(
+        "The audio sink has been finished or the session has been dropped".to_owned(),
+    )
+}
+
+/// The receiving end of an AudioSink, which is passed to RealtimeSession::run in place of a reader.
+///
+/// Every read returns data from at most one frame, so frame boundaries are kept when the audio is sent.
+/// The reader reaches the end of the stream once AudioSink::finish is called or every AudioSink has been dropped.
+#[derive(Debug)]
+pub struct AudioSinkReader {
+    receiver: UnboundedReceiver<SinkMessage>,
+    current: Bytes,
+    finished: bool,
+}
+
+impl AsyncRead for AudioSinkReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        while self.current.is_empty() {
+            if self.finished {
+                return Poll::Ready(Ok(()));
+            }
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(SinkMessage::Frame(frame))) => self.current = frame,
+                Poll::Ready(Some(SinkMessage::Finish)) | Poll::Ready(None) => {
+                    self.finished = true;
+                    self.receiver.close();
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = self.current.len().min(buf.remaining());
+        buf.put_slice(&self.current[..len]);
+        self.current.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Creates a connected AudioSink and AudioSinkReader
+pub(crate) fn audio_sink() -> (AudioSink, AudioSinkReader) {
+    let (sender, receiver) = unbounded_channel();
+    let reader = AudioSinkReader {
+        receiver,
+        current: Bytes::new(),
+        finished: false,
+    };
+    let sink = AudioSink {
+        sender,
+        finished: Arc::new(AtomicBool::new(false)),
+    };
+    (sink, reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_audio_sink_keeps_frames() {
+        let (sink, mut reader) = audio_sink();
+        let other_sink = sink.clone();
+        sink.push(vec![1u8; 320]).unwrap();
+        other_sink.push(vec![2u8; 160]).unwrap();
+        other_sink.finish().unwrap();
+        assert!(sink.push(vec![3u8; 160]).is_err());
+
+        let mut buffer = vec![0u8; 8192];
+        assert_eq!(reader.read(&mut buffer).await.unwrap(), 320);
+        assert_eq!(reader.read(&mut buffer).await.unwrap(), 160);
+        assert_eq!(buffer[0], 2);
+        assert_eq!(reader.read(&mut buffer).await.unwrap(), 0);
+    }
+}
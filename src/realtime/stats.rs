@@ -0,0 +1,311 @@
+//! Latency and throughput metrics for realtime sessions, read through SessionControl::stats.
+//!
+//! The latency of a transcript is the time from sending the chunk of audio which holds its end time to receiving it.
+//! Mapping end times to chunks needs the byte rate of the audio, so latencies are only measured for raw audio
+//! with the encoding and sample rate set.
+//!
+//! Besides the SessionStats snapshot, the metrics are emitted through the metrics facade as they are measured,
+//! and as a tracing event when a run finishes.
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use super::ReadMessage;
+
+/// A histogram of latencies with fixed buckets, from tens of milliseconds up to several seconds.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LatencyHistogram {
+    /// The number of latencies in each bucket, the last one being for latencies above every bound
+    counts: [u64; 12],
+    count: u64,
+    sum: Duration,
+    min: Option<Duration>,
+    max: Option<Duration>,
+}
+
+impl LatencyHistogram {
+    /// The upper bounds of the buckets, each of which includes its bound
+    pub const BOUNDS: [Duration; 11] = [
+        Duration::from_millis(50),
+        Duration::from_millis(100),
+        Duration::from_millis(250),
+        Duration::from_millis(500),
+        Duration::from_millis(750),
+        Duration::from_millis(1000),
+        Duration::from_millis(1500),
+        Duration::from_millis(2000),
+        Duration::from_millis(3000),
+        Duration::from_millis(5000),
+        Duration::from_millis(10000),
+    ];
+
+    /// Adds a latency to the histogram
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = Self::BOUNDS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(Self::BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+        self.min = Some(self.min.map_or(latency, |min| min.min(latency)));
+        self.max = Some(self.max.map_or(latency, |max| max.max(latency)));
+    }
+
+    /// The number of latencies recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The upper bound of each bucket along with the number of latencies in it. The last bucket has no upper bound
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        Self::BOUNDS
+            .iter()
+            .map(|bound| Some(*bound))
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+
+    /// The shortest latency recorded
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// The longest latency recorded
+    pub fn max(&self) -> Option<Duration> {
+        self.max
+    }
+
+    /// The mean of the latencies recorded
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| self.sum / self.count as u32)
+    }
+
+    /// An estimate of the given percentile, from 0 to 100, as the upper bound of the bucket it falls in
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let max = self.max?;
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return Some(bound.map_or(max, |bound| bound.min(max)));
+            }
+        }
+        Some(max)
+    }
+}
+
+/// A snapshot of the metrics of the current or last run of a realtime session.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SessionStats {
+    /// The number of audio chunks read and sent, not counting chunks replayed after a reconnect
+    pub audio_chunks_sent: u64,
+    /// The number of bytes of audio read and sent, not counting chunks replayed after a reconnect
+    pub audio_bytes_sent: u64,
+    /// The duration of the audio sent. This is only known for raw audio with the encoding and sample rate set
+    pub audio_seconds_sent: Option<f64>,
+    /// The end time of the last final transcript, i.e. how much of the audio has been transcribed, in seconds
+    pub transcribed_seconds: f64,
+    /// How far the final transcripts are behind the audio sent, in seconds of audio
+    pub server_lag: Option<f64>,
+    /// The seconds of audio transcribed per second from the first audio being sent until now, or until the run finished.
+    /// It is close to 1.0 for a live source the server keeps up with, and higher when audio is sent faster than real time
+    pub real_time_factor: Option<f64>,
+    /// The time from sending the first audio to receiving the first partial transcript
+    pub time_to_first_partial: Option<Duration>,
+    /// The time from sending the first audio to receiving the first final transcript
+    pub time_to_first_final: Option<Duration>,
+    /// The latency of each partial transcript
+    pub partial_latency: LatencyHistogram,
+    /// The latency of each final transcript
+    pub final_latency: LatencyHistogram,
+}
+
+/// Collects the metrics of a run of a session, shared between the session and its SessionControl handles
+#[derive(Debug, Default)]
+pub(crate) struct StatsCollector {
+    bytes_per_second: Option<u32>,
+    first_sent: Option<Instant>,
+    /// When the run finished, after which the real time factor no longer changes
+    finished: Option<Instant>,
+    /// The end of each chunk sent in seconds of audio, with when it was sent, from the last final onwards
+    sent: VecDeque<(f64, Instant)>,
+    stats: SessionStats,
+}
+
+impl StatsCollector {
+    /// Clears the metrics at the start of a run
+    pub(crate) fn reset(&mut self, bytes_per_second: Option<u32>) {
+        *self = Self {
+            bytes_per_second: bytes_per_second.filter(|bytes_per_second| *bytes_per_second > 0),
+            ..Self::default()
+        };
+    }
+
+    /// Records a chunk of audio read from the audio source and sent to the server
+    pub(crate) fn audio_sent(&mut self, len: usize) {
+        let now = Instant::now();
+        self.first_sent.get_or_insert(now);
+        self.stats.audio_chunks_sent += 1;
+        self.stats.audio_bytes_sent += len as u64;
+        metrics::counter!("realtime_audio_chunks_sent").increment(1);
+        metrics::counter!("realtime_audio_bytes_sent").increment(len as u64);
+        if let Some(bytes_per_second) = self.bytes_per_second {
+            let audio_end = self.stats.audio_bytes_sent as f64 / bytes_per_second as f64;
+            self.sent.push_back((audio_end, now));
+        }
+    }
+
+    /// Records a message from the server, measuring the latency of transcripts
+    pub(crate) fn received(&mut self, message: &ReadMessage) {
+        let (end_time, is_final) = match message {
+            ReadMessage::AddPartialTranscript(mess) => (mess.metadata.end_time, false),
+            ReadMessage::AddTranscript(mess) => (mess.metadata.end_time, true),
+            _ => return,
+        };
+        let now = Instant::now();
+        let end_time = end_time as f64;
+        if let Some(first_sent) = self.first_sent {
+            let first = if is_final {
+                &mut self.stats.time_to_first_final
+            } else {
+                &mut self.stats.time_to_first_partial
+            };
+            if first.is_none() {
+                let elapsed = now - first_sent;
+                *first = Some(elapsed);
+                let kind = if is_final { "final" } else { "partial" };
+                metrics::histogram!("realtime_time_to_first_transcript_seconds", "kind" => kind)
+                    .record(elapsed);
+            }
+        }
+        // allow for the rounding of timestamps by the server
+        let sent_at = self
+            .sent
+            .iter()
+            .find(|(audio_end, _)| *audio_end >= end_time - 0.001)
+            .or_else(|| self.sent.back())
+            .map(|(_, sent_at)| *sent_at);
+        if let Some(sent_at) = sent_at {
+            let latency = now - sent_at;
+            let kind = if is_final { "final" } else { "partial" };
+            tracing::debug!(
+                kind,
+                end_time,
+                latency_ms = latency.as_millis() as u64,
+                "transcript latency"
+            );
+            metrics::histogram!("realtime_transcript_latency_seconds", "kind" => kind)
+                .record(latency);
+            if is_final {
+                self.stats.final_latency.record(latency);
+            } else {
+                self.stats.partial_latency.record(latency);
+            }
+        }
+        if is_final {
+            self.stats.transcribed_seconds = self.stats.transcribed_seconds.max(end_time);
+            while self
+                .sent
+                .front()
+                .is_some_and(|(audio_end, _)| *audio_end < end_time)
+            {
+                self.sent.pop_front();
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> SessionStats {
+        let mut stats = self.stats.clone();
+        stats.audio_seconds_sent = self
+            .bytes_per_second
+            .map(|bytes_per_second| stats.audio_bytes_sent as f64 / bytes_per_second as f64);
+        stats.server_lag = stats
+            .audio_seconds_sent
+            .map(|seconds| (seconds - stats.transcribed_seconds).max(0.0));
+        stats.real_time_factor = self
+            .first_sent
+            .map(|first_sent| {
+                let end = self.finished.unwrap_or_else(Instant::now);
+                end.saturating_duration_since(first_sent).as_secs_f64()
+            })
+            .filter(|elapsed| *elapsed > 0.0)
+            .map(|elapsed| stats.transcribed_seconds / elapsed);
+        stats
+    }
+
+    /// Marks the end of a run, and emits its metrics as a tracing event
+    pub(crate) fn finish(&mut self) {
+        self.finished = Some(Instant::now());
+        let stats = self.snapshot();
+        if let Some(server_lag) = stats.server_lag {
+            metrics::histogram!("realtime_server_lag_seconds").record(server_lag);
+        }
+        tracing::info!(
+            audio_chunks_sent = stats.audio_chunks_sent,
+            audio_bytes_sent = stats.audio_bytes_sent,
+            audio_seconds_sent = ?stats.audio_seconds_sent,
+            transcribed_seconds = stats.transcribed_seconds,
+            server_lag = ?stats.server_lag,
+            real_time_factor = ?stats.real_time_factor,
+            time_to_first_partial = ?stats.time_to_first_partial,
+            time_to_first_final = ?stats.time_to_first_final,
+            partial_latency_p50 = ?stats.partial_latency.percentile(50.0),
+            partial_latency_p95 = ?stats.partial_latency.percentile(95.0),
+            final_latency_p50 = ?stats.final_latency.percentile(50.0),
+            final_latency_p95 = ?stats.final_latency.percentile(95.0),
+            "realtime session stats"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_transcript_latency() {
+        let mut collector = StatsCollector::default();
+        collector.reset(Some(32000));
+        collector.audio_sent(3200);
+        collector.audio_sent(3200);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let partial = r#"{"message": "AddPartialTranscript", "metadata": {"start_time": 0.0, "end_time": 0.1, "transcript": ""}, "results": []}"#;
+        collector.received(&serde_json::from_str(partial).unwrap());
+        let transcript = r#"{"message": "AddTranscript", "metadata": {"start_time": 0.0, "end_time": 0.15, "transcript": ""}, "results": []}"#;
+        collector.received(&serde_json::from_str(transcript).unwrap());
+
+        let stats = collector.snapshot();
+        assert_eq!(stats.audio_chunks_sent, 2);
+        assert_eq!(stats.audio_bytes_sent, 6400);
+        assert_eq!(stats.audio_seconds_sent, Some(0.2));
+        assert!(stats.time_to_first_partial.unwrap() >= Duration::from_millis(20));
+        assert_eq!(stats.partial_latency.count(), 1);
+        assert_eq!(stats.final_latency.count(), 1);
+        assert!(stats.final_latency.min().unwrap() >= Duration::from_millis(20));
+        assert!((stats.server_lag.unwrap() - 0.05).abs() < 1e-6);
+
+        // the real time factor stops changing once the run has finished
+        collector.finish();
+        let real_time_factor = collector.snapshot().real_time_factor;
+        assert!(real_time_factor.is_some());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(collector.snapshot().real_time_factor, real_time_factor);
+    }
+
+    #[test]
+    fn test_histogram_percentile() {
+        let mut histogram = LatencyHistogram::default();
+        for millis in [40, 90, 200, 400, 12000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(250)));
+        assert_eq!(
+            histogram.percentile(100.0),
+            Some(Duration::from_millis(12000))
+        );
+        assert_eq!(histogram.mean(), Some(Duration::from_millis(2546)));
+    }
+}
@@ -0,0 +1,373 @@
+//! The connection a realtime session runs over, so a session can be run over something other than a tokio-tungstenite websocket.
+use base64::{engine::general_purpose, Engine as _};
+use futures::{
+    channel::mpsc,
+    future::{self, BoxFuture},
+    stream::BoxStream,
+    FutureExt, Sink, SinkExt, StreamExt,
+};
+use http::Request;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio_tungstenite::{
+    client_async_tls_with_config,
+    tungstenite::{self, protocol::WebSocketConfig, Message},
+};
+use url::Url;
+
+#[cfg(test)]
+use std::println as error;
+
+#[cfg(not(test))]
+use log::error;
+
+use super::connector;
+use super::error::Result;
+use super::RealtimeError;
+use crate::connection::ConnectionOptions;
+
+/// A single frame sent to or received from the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    /// A JSON message
+    Text(String),
+    /// A chunk of audio
+    Binary(Vec<u8>),
+    /// A keepalive ping. Transports without pings may drop it
+    Ping(Vec<u8>),
+    /// The answer to a ping
+    Pong(Vec<u8>),
+}
+
+impl Frame {
+    /// Returns false for control frames such as pings and pongs, which carry no message
+    pub fn is_data(&self) -> bool {
+        matches!(self, Frame::Text(_) | Frame::Binary(_))
+    }
+
+    /// Consumes the frame, returning its payload
+    pub fn into_data(self) -> Vec<u8> {
+        match self {
+            Frame::Text(text) => text.into_bytes(),
+            Frame::Binary(data) | Frame::Ping(data) | Frame::Pong(data) => data,
+        }
+    }
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(data) => Message::Binary(data),
+            Frame::Ping(data) => Message::Ping(data),
+            Frame::Pong(data) => Message::Pong(data),
+        }
+    }
+}
+
+/// The most messages a websocket queues without being able to write them, after which a send fails with
+/// SendQueueFull and is retried as set out by the RetryPolicy of the session
+const MAX_SEND_QUEUE: usize = 64;
+
+/// The sending half of a connection opened by a Transport
+pub type FrameSink = Pin<Box<dyn Sink<Frame, Error = RealtimeError> + Send>>;
+
+/// The receiving half of a connection opened by a Transport. The stream ends when the connection closes
+pub type FrameStream = BoxStream<'static, Result<Frame>>;
+
+/// Opens connections to the realtime server for a RealtimeSession.
+///
+/// A session opens a new connection every time it starts, and again on every reconnect if a ReconnectPolicy is set.
+/// WebsocketTransport is used by default, and LoopbackTransport runs a session against an in-process server.
+pub trait Transport: Send {
+    /// Opens a connection to the server at url, authenticated with auth_token
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a Url,
+        auth_token: &'a str,
+    ) -> BoxFuture<'a, Result<(FrameSink, FrameStream)>>;
+}
+
+/// The default Transport, a tokio-tungstenite websocket which connects as set out in its ConnectionOptions.
+#[derive(Clone, Debug, Default)]
+pub struct WebsocketTransport {
+    options: ConnectionOptions,
+}
+
+impl WebsocketTransport {
+    /// Creates a websocket transport which connects with the given options
+    pub fn new(options: ConnectionOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl Transport for WebsocketTransport {
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a Url,
+        auth_token: &'a str,
+    ) -> BoxFuture<'a, Result<(FrameSink, FrameStream)>> {
+        async move {
+            let sec_key: String = thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect();
+            let b64 = general_purpose::STANDARD.encode(sec_key);
+
+            let authority = url.authority();
+            let host = authority
+                .find('@')
+                .map(|idx| authority.split_at(idx + 1).1)
+                .unwrap_or_else(|| authority);
+
+            if host.is_empty() {
+                return Err(RealtimeError::Config("uri host was empty".to_owned()));
+            }
+            let auth_header = format!("Bearer {}", auth_token);
+
+            let req = Request::builder()
+                .method("GET")
+                .header("Host", host)
+                .header("Connection", "keep-alive, Upgrade")
+                .header("Upgrade", "websocket")
+                .header("Sec-WebSocket-Version", "13")
+                .header("Sec-WebSocket-Key", b64)
+                .header("Authorization", auth_header)
+                .uri(url.as_str())
+                .body(())?;
+
+            let tls_connector = connector::tls_connector(&self.options)?;
+            let tcp_stream = connector::connect_stream(url, self.options.proxy.as_ref()).await?;
+            let config = WebSocketConfig {
+                max_send_queue: Some(MAX_SEND_QUEUE),
+                ..WebSocketConfig::default()
+            };
+            let (stream, res) =
+                client_async_tls_with_config(req, tcp_stream, Some(config), Some(tls_connector))
+                    .await?;
+            if let Some(resp) = res.body() {
+                error!("failed to connect {:?}", resp);
+            }
+
+            let (writer, reader) = stream.split();
+            let sink: FrameSink = Box::pin(
+                writer
+                    .sink_map_err(RealtimeError::from)
+                    .with(|frame: Frame| {
+                        future::ready(Ok::<_, RealtimeError>(Message::from(frame)))
+                    }),
+            );
+            let stream = reader
+                .filter_map(|message| {
+                    future::ready(match message {
+                        Ok(Message::Text(text)) => Some(Ok(Frame::Text(text))),
+                        Ok(Message::Binary(data)) => Some(Ok(Frame::Binary(data))),
+                        Ok(Message::Ping(data)) => Some(Ok(Frame::Ping(data))),
+                        Ok(Message::Pong(data)) => Some(Ok(Frame::Pong(data))),
+                        // the stream ends straight after a close frame
+                        Ok(Message::Close(_)) | Ok(Message::Frame(_)) => None,
+                        Err(err) => Some(Err(RealtimeError::from(err))),
+                    })
+                })
+                .boxed();
+            Ok((sink, stream))
+        }
+        .boxed()
+    }
+}
+
+/// Creates an in-memory Transport along with the server end of it, e.g. to test code which drives a RealtimeSession.
+///
+/// # Example
+///
+/// ```
+/// let (transport, mut server) = loopback();
+/// let (mut rt_session, _) = RealtimeSession::with_transport("YOUR_API_KEY".to_owned(), None, transport).unwrap();
+///
+/// tokio::spawn(async move {
+///     while let Some(mut connection) = server.accept().await {
+///         while let Some(frame) = connection.recv().await {
+///             println!("{:?}", frame);
+///         }
+///     }
+/// });
+/// ```
+pub fn loopback() -> (LoopbackTransport, LoopbackServer) {
+    let (sender, receiver) = mpsc::unbounded();
+    (
+        LoopbackTransport {
+            connections: sender,
+            max_send_queue: None,
+        },
+        LoopbackServer {
+            connections: receiver,
+        },
+    )
+}
+
+/// A Transport whose connections are accepted by a LoopbackServer in the same process, created with loopback.
+#[derive(Clone, Debug)]
+pub struct LoopbackTransport {
+    connections: mpsc::UnboundedSender<LoopbackConnection>,
+    max_send_queue: Option<usize>,
+}
+
+impl LoopbackTransport {
+    /// Limits how many frames the session may send which the server has not received yet, like the send queue of a websocket.
+    /// Once the limit is reached, sending fails with SendQueueFull. By default there is no limit
+    pub fn set_max_send_queue(&mut self, max_send_queue: Option<usize>) {
+        self.max_send_queue = max_send_queue;
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn connect<'a>(
+        &'a mut self,
+        url: &'a Url,
+        auth_token: &'a str,
+    ) -> BoxFuture<'a, Result<(FrameSink, FrameStream)>> {
+        let (client_sender, server_receiver) = mpsc::unbounded();
+        let (server_sender, client_receiver) = mpsc::unbounded();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let connection = LoopbackConnection {
+            url: url.clone(),
+            auth_token: auth_token.to_owned(),
+            sender: server_sender,
+            receiver: server_receiver,
+            queued: queued.clone(),
+        };
+        let res = match self.connections.unbounded_send(connection) {
+            Ok(()) => {
+                let sink: FrameSink = Box::pin(LoopbackSink {
+                    sender: client_sender,
+                    queued,
+                    max_send_queue: self.max_send_queue,
+                });
+                let stream = client_receiver.map(Ok).boxed();
+                Ok((sink, stream))
+            }
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into()),
+        };
+        future::ready(res).boxed()
+    }
+}
+
+/// The sending half of a loopback connection, which fails once the server has max_send_queue frames left to receive
+struct LoopbackSink {
+    sender: mpsc::UnboundedSender<Frame>,
+    queued: Arc<AtomicUsize>,
+    max_send_queue: Option<usize>,
+}
+
+impl Sink<Frame> for LoopbackSink {
+    type Error = RealtimeError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender)
+            .poll_ready(cx)
+            .map_err(|_| RealtimeError::connection_closed())
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<()> {
+        let this = self.get_mut();
+        if let Some(max_send_queue) = this.max_send_queue {
+            if this.queued.load(Ordering::SeqCst) >= max_send_queue {
+                return Err(tungstenite::Error::SendQueueFull(Message::from(frame)).into());
+            }
+        }
+        Pin::new(&mut this.sender)
+            .start_send(frame)
+            .map_err(|_| RealtimeError::connection_closed())?;
+        this.queued.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender)
+            .poll_flush(cx)
+            .map_err(|_| RealtimeError::connection_closed())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().sender)
+            .poll_close(cx)
+            .map_err(|_| RealtimeError::connection_closed())
+    }
+}
+
+/// The server end of a LoopbackTransport, which accepts the connections opened by the session.
+#[derive(Debug)]
+pub struct LoopbackServer {
+    connections: mpsc::UnboundedReceiver<LoopbackConnection>,
+}
+
+impl LoopbackServer {
+    /// Waits for the session to open a connection. Returns None once every LoopbackTransport has been dropped
+    pub async fn accept(&mut self) -> Option<LoopbackConnection> {
+        self.connections.next().await
+    }
+}
+
+/// The server side of a single connection opened through a LoopbackTransport. Dropping it closes the connection.
+#[derive(Debug)]
+pub struct LoopbackConnection {
+    /// The URL the session connected to
+    pub url: Url,
+    /// The auth token the session connected with
+    pub auth_token: String,
+    sender: mpsc::UnboundedSender<Frame>,
+    receiver: mpsc::UnboundedReceiver<Frame>,
+    /// The number of frames sent by the session which have not been received yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl LoopbackConnection {
+    /// Sends a frame to the session
+    pub fn send(&self, frame: Frame) -> Result<()> {
+        self.sender
+            .unbounded_send(frame)
+            .map_err(|_| RealtimeError::connection_closed())
+    }
+
+    /// Waits for the next frame from the session. Returns None once the session has closed the connection
+    pub async fn recv(&mut self) -> Option<Frame> {
+        let frame = self.receiver.next().await?;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_loopback_round_trip() {
+        let (mut transport, mut server) = loopback();
+        let url = Url::parse("ws://localhost/v2").unwrap();
+        let (mut sink, mut stream) = transport.connect(&url, "token").await.unwrap();
+        let mut connection = server.accept().await.unwrap();
+        assert_eq!(connection.auth_token, "token");
+
+        sink.send(Frame::Binary(vec![1, 2, 3])).await.unwrap();
+        assert_eq!(connection.recv().await, Some(Frame::Binary(vec![1, 2, 3])));
+        connection.send(Frame::Text("{}".to_owned())).unwrap();
+        assert_eq!(
+            stream.next().await.unwrap().unwrap(),
+            Frame::Text("{}".to_owned())
+        );
+
+        sink.close().await.unwrap();
+        assert_eq!(connection.recv().await, None);
+        drop(connection);
+        assert!(stream.next().await.is_none());
+
+        drop(server);
+        assert!(transport.connect(&url, "token").await.is_err());
+    }
+}
@@ -0,0 +1,361 @@
+//! Assembles recognition results into text, sentences and speaker turns, for both realtime and batch transcripts.
+//!
+//! The server sends a transcript as a list of items, each a word, a punctuation mark or a speaker change.
+//! Punctuation says which of its neighbours it attaches to, so the text has no space around it on that side,
+//! and sentence-ending punctuation is marked as such. TranscriptBuilder applies these rules to render the items.
+
+/// Which neighbours an item joins onto without a space.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Attachment {
+    /// Spaces on both sides, as for a word
+    #[default]
+    None,
+    /// No space before the item, as for a comma
+    Previous,
+    /// No space after the item, as for an opening bracket
+    Next,
+    /// No space on either side, as for a hyphen
+    Both,
+}
+
+/// The kind of a TranscriptItem.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ItemKind {
+    /// A word
+    Word,
+    /// A punctuation mark
+    Punctuation,
+    /// A change of speaker, which carries no text
+    SpeakerChange,
+}
+
+/// A single recognised item, taken from a realtime or batch RecognitionResult.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptItem {
+    /// The kind of item
+    pub kind: ItemKind,
+    /// The text of the most likely alternative, empty for a speaker change
+    pub content: String,
+    /// The start of the item in the audio, in seconds
+    pub start_time: f32,
+    /// The end of the item in the audio, in seconds
+    pub end_time: f32,
+    /// Which neighbours the item joins onto
+    pub attaches_to: Attachment,
+    /// Whether the item ends a sentence
+    pub is_eos: bool,
+    /// The speaker of the most likely alternative, if diarization is enabled
+    pub speaker: Option<String>,
+}
+
+impl TranscriptItem {
+    /// The attachment of punctuation the server did not give one for. Punctuation is far more often closing than opening
+    fn default_attachment(kind: ItemKind) -> Attachment {
+        match kind {
+            ItemKind::Punctuation => Attachment::Previous,
+            _ => Attachment::None,
+        }
+    }
+}
+
+#[cfg(feature = "realtime")]
+impl From<&crate::realtime::models::RecognitionResult> for TranscriptItem {
+    fn from(result: &crate::realtime::models::RecognitionResult) -> Self {
+        use crate::realtime::models::recognition_result::{AttachesTo, Type};
+        let kind = match result.type_value {
+            Type::Word => ItemKind::Word,
+            Type::Punctuation => ItemKind::Punctuation,
+            Type::SpeakerChange => ItemKind::SpeakerChange,
+        };
+        let alternative = result
+            .alternatives
+            .as_ref()
+            .and_then(|alternatives| alternatives.first());
+        Self {
+            kind,
+            content: alternative
+                .map(|alternative| alternative.content.clone())
+                .unwrap_or_default(),
+            start_time: result.start_time,
+            end_time: result.end_time,
+            attaches_to: match result.attaches_to {
+                Some(AttachesTo::None) => Attachment::None,
+                Some(AttachesTo::Previous) => Attachment::Previous,
+                Some(AttachesTo::Next) => Attachment::Next,
+                Some(AttachesTo::Both) => Attachment::Both,
+                None => TranscriptItem::default_attachment(kind),
+            },
+            is_eos: result.is_eos.unwrap_or_default(),
+            speaker: alternative.and_then(|alternative| alternative.speaker.clone()),
+        }
+    }
+}
+
+#[cfg(feature = "batch")]
+impl From<&crate::batch::models::RecognitionResult> for TranscriptItem {
+    fn from(result: &crate::batch::models::RecognitionResult) -> Self {
+        use crate::batch::models::recognition_result::Type;
+        let kind = match result.type_value {
+            Type::Word => ItemKind::Word,
+            Type::Punctuation => ItemKind::Punctuation,
+        };
+        let alternative = result
+            .alternatives
+            .as_ref()
+            .and_then(|alternatives| alternatives.first());
+        Self {
+            kind,
+            content: alternative
+                .map(|alternative| alternative.content.clone())
+                .unwrap_or_default(),
+            start_time: result.start_time,
+            end_time: result.end_time,
+            // batch results do not carry attaches_to
+            attaches_to: TranscriptItem::default_attachment(kind),
+            is_eos: result.is_eos.unwrap_or_default(),
+            speaker: alternative.and_then(|alternative| alternative.speaker.clone()),
+        }
+    }
+}
+
+/// A sentence of a transcript, ending with sentence-ending punctuation, a change of speaker or the end of the transcript.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sentence {
+    /// The text of the sentence
+    pub text: String,
+    /// The start of the first item of the sentence, in seconds
+    pub start_time: f32,
+    /// The end of the last item of the sentence, in seconds
+    pub end_time: f32,
+    /// The speaker of the sentence, if diarization is enabled
+    pub speaker: Option<String>,
+}
+
+/// An unbroken stretch of a transcript spoken by one speaker.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpeakerTurn {
+    /// The speaker, if diarization is enabled. Without diarization the whole transcript is one turn
+    pub speaker: Option<String>,
+    /// The text of the turn
+    pub text: String,
+    /// The start of the first item of the turn, in seconds
+    pub start_time: f32,
+    /// The end of the last item of the turn, in seconds
+    pub end_time: f32,
+}
+
+/// Collects the results of a transcript and renders them into correctly spaced text.
+///
+/// # Example
+///
+/// ```
+/// let mut builder = TranscriptBuilder::new();
+/// while let Some(message) = receive_channel.recv().await {
+///     if let ReadMessage::AddTranscript(transcript) = message {
+///         builder.add_transcript(&transcript);
+///     }
+/// }
+/// println!("{}", builder.text());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TranscriptBuilder {
+    items: Vec<TranscriptItem>,
+}
+
+impl TranscriptBuilder {
+    /// Creates an empty builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends the results of a final realtime transcript. Partial transcripts should not be added, as they are superseded
+    #[cfg(feature = "realtime")]
+    pub fn add_transcript(&mut self, transcript: &crate::realtime::models::AddTranscript) {
+        self.extend(transcript.results.iter());
+    }
+
+    /// Appends the results of a batch transcript
+    #[cfg(feature = "batch")]
+    pub fn add_batch_transcript(
+        &mut self,
+        transcript: &crate::batch::models::RetrieveTranscriptResponse,
+    ) {
+        self.extend(transcript.results.iter());
+    }
+
+    /// Appends recognition results, or any other items which convert into TranscriptItems
+    pub fn extend<I: Into<TranscriptItem>>(&mut self, results: impl IntoIterator<Item = I>) {
+        self.items.extend(results.into_iter().map(Into::into));
+    }
+
+    /// Appends a single item
+    pub fn push(&mut self, item: TranscriptItem) {
+        self.items.push(item);
+    }
+
+    /// The items collected so far
+    pub fn items(&self) -> &[TranscriptItem] {
+        &self.items
+    }
+
+    /// Removes all of the items collected so far
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    /// Renders the whole transcript as a single line of text
+    pub fn text(&self) -> String {
+        render(&self.items)
+    }
+
+    /// Splits the transcript into sentences
+    pub fn sentences(&self) -> Vec<Sentence> {
+        let mut sentences = vec![];
+        for turn in self.turn_items() {
+            let mut start = 0;
+            for (index, item) in turn.iter().enumerate() {
+                if item.is_eos || index + 1 == turn.len() {
+                    let items = &turn[start..=index];
+                    sentences.push(Sentence {
+                        text: render(items),
+                        start_time: items[0].start_time,
+                        end_time: items[items.len() - 1].end_time,
+                        speaker: speaker(items),
+                    });
+                    start = index + 1;
+                }
+            }
+        }
+        sentences
+    }
+
+    /// Splits the transcript into turns, at every change of speaker
+    pub fn turns(&self) -> Vec<SpeakerTurn> {
+        self.turn_items()
+            .into_iter()
+            .map(|items| SpeakerTurn {
+                speaker: speaker(items),
+                text: render(items),
+                start_time: items[0].start_time,
+                end_time: items[items.len() - 1].end_time,
+            })
+            .collect()
+    }
+
+    /// Groups the items by turn, dropping speaker change items. Punctuation stays with the turn of the word before it
+    fn turn_items(&self) -> Vec<&[TranscriptItem]> {
+        let mut turns = vec![];
+        let mut start = 0;
+        let mut current_speaker: Option<&str> = None;
+        for (index, item) in self.items.iter().enumerate() {
+            let new_speaker = match item.kind {
+                ItemKind::SpeakerChange => true,
+                ItemKind::Word => {
+                    item.speaker.is_some() && item.speaker.as_deref() != current_speaker
+                }
+                ItemKind::Punctuation => false,
+            };
+            if new_speaker && index > start {
+                turns.push(&self.items[start..index]);
+                start = index;
+            }
+            if item.kind == ItemKind::SpeakerChange {
+                start = index + 1;
+            } else if item.kind == ItemKind::Word && item.speaker.is_some() {
+                current_speaker = item.speaker.as_deref();
+            }
+        }
+        if start < self.items.len() {
+            turns.push(&self.items[start..]);
+        }
+        turns
+    }
+}
+
+/// The speaker of the first word of the items which has one
+fn speaker(items: &[TranscriptItem]) -> Option<String> {
+    items
+        .iter()
+        .find(|item| item.kind == ItemKind::Word && item.speaker.is_some())
+        .and_then(|item| item.speaker.clone())
+}
+
+/// Joins the items into text, leaving out the space on either side of an item where it attaches to its neighbour
+fn render(items: &[TranscriptItem]) -> String {
+    let mut text = String::new();
+    let mut attach_next = false;
+    for item in items {
+        append(&mut text, &mut attach_next, item);
+    }
+    text
+}
+
+/// Appends a single item to rendered text. attach_next carries whether the last item appended attaches to the next one
+pub(crate) fn append(text: &mut String, attach_next: &mut bool, item: &TranscriptItem) {
+    if item.kind == ItemKind::SpeakerChange || item.content.is_empty() {
+        return;
+    }
+    let attach_previous = matches!(item.attaches_to, Attachment::Previous | Attachment::Both);
+    if !text.is_empty() && !*attach_next && !attach_previous {
+        text.push(' ');
+    }
+    text.push_str(&item.content);
+    *attach_next = matches!(item.attaches_to, Attachment::Next | Attachment::Both);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(content: &str, kind: ItemKind, speaker: &str) -> TranscriptItem {
+        TranscriptItem {
+            kind,
+            content: content.to_owned(),
+            start_time: 0.0,
+            end_time: 0.0,
+            attaches_to: TranscriptItem::default_attachment(kind),
+            is_eos: matches!(content, "." | "?"),
+            speaker: Some(speaker.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_render_sentences_and_turns() {
+        let word = |content| item(content, ItemKind::Word, "S1");
+        let punctuation = |content| item(content, ItemKind::Punctuation, "S1");
+        let mut builder = TranscriptBuilder::new();
+        builder.extend(vec![
+            word("Hello"),
+            punctuation(","),
+            word("well"),
+            TranscriptItem {
+                attaches_to: Attachment::Both,
+                ..punctuation("-")
+            },
+            word("known"),
+            word("world"),
+            punctuation("."),
+            word("Bye"),
+            item("", ItemKind::SpeakerChange, "S1"),
+            item("How", ItemKind::Word, "S2"),
+            item("are", ItemKind::Word, "S2"),
+            item("you", ItemKind::Word, "S2"),
+            item("?", ItemKind::Punctuation, "S2"),
+        ]);
+
+        assert_eq!(builder.text(), "Hello, well-known world. Bye How are you?");
+        let sentences: Vec<String> = builder
+            .sentences()
+            .into_iter()
+            .map(|sentence| sentence.text)
+            .collect();
+        assert_eq!(
+            sentences,
+            vec!["Hello, well-known world.", "Bye", "How are you?"]
+        );
+        let turns = builder.turns();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[1].speaker.as_deref(), Some("S2"));
+        assert_eq!(turns[1].text, "How are you?");
+    }
+}